@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use crate::{jobs, run_turn, ChatHistory, ChatMessage, ChatRole, ModelIdentArg, TurnContext};
+
+/// How one model did on the benchmark prompt.
+#[derive(Debug)]
+pub struct BenchmarkRun {
+    /// From sending the request, handshake included, to the first fragment
+    pub first_token: Duration,
+    /// From the first fragment to the end of the stream
+    pub streaming: Duration,
+    pub chars: usize,
+}
+
+impl BenchmarkRun {
+    fn chars_per_second(&self) -> f64 {
+        match self.streaming.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.chars as f64 / secs,
+        }
+    }
+}
+
+/// Lays out the results as a table, one row per model.
+pub fn table(results: &[(ModelIdentArg, Result<BenchmarkRun, String>)]) -> String {
+    let mut table = format!(
+        "{:<12} {:>12} {:>10} {:>8}\n",
+        "model", "first token", "chars/s", "chars"
+    );
+    for (model, result) in results {
+        let row = match result {
+            Ok(run) => format!(
+                "{:<12} {:>10}ms {:>10.1} {:>8}",
+                model.as_str(),
+                run.first_token.as_millis(),
+                run.chars_per_second(),
+                run.chars
+            ),
+            Err(e) => format!("{:<12} error: {e}", model.as_str()),
+        };
+        table.push_str(&row);
+        table.push('\n');
+    }
+    table
+}
+
+/// Sends `prompt` to each of `models`, `jobs` at a time, and prints how they compare.
+/// With a single job they go one after the other so they don't compete for bandwidth.
+/// Nothing gets saved.
+pub async fn run(ctx: &TurnContext<'_>, models: &[ModelIdentArg], prompt: &str, jobs: usize) {
+    let mut results = Vec::new();
+    let runs = models
+        .iter()
+        .map(|&model| async move { (model, measure(ctx, model, prompt).await) });
+    jobs::run_in_order(runs, jobs, |result| results.push(result)).await;
+
+    print!("{}", table(&results));
+}
+
+/// How `model` does on `prompt`.
+async fn measure(
+    ctx: &TurnContext<'_>,
+    model: ModelIdentArg,
+    prompt: &str,
+) -> Result<BenchmarkRun, String> {
+    let mut chat_history = ChatHistory::new(
+        model.to_model(),
+        vec![ChatMessage {
+            role: ChatRole::User,
+            content: prompt.to_owned(),
+            truncated: false,
+        }],
+    );
+
+    let start = Instant::now();
+    let mut first_token = None;
+    let result = run_turn(ctx, &mut chat_history, &mut |_: &str| {
+        first_token.get_or_insert_with(Instant::now);
+    })
+    .await;
+    let end = Instant::now();
+
+    match (result, first_token) {
+        (Ok(reply), Some(first_token)) => Ok(BenchmarkRun {
+            first_token: first_token - start,
+            streaming: end - first_token,
+            chars: reply.chars().count(),
+        }),
+        (Ok(_), None) => Err("empty reply".to_owned()),
+        (Err(e), _) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_lists_every_model() {
+        let results = [
+            (
+                ModelIdentArg::GPT4oMini,
+                Ok(BenchmarkRun {
+                    first_token: Duration::from_millis(412),
+                    streaming: Duration::from_secs(2),
+                    chars: 900,
+                }),
+            ),
+            (ModelIdentArg::Claude3, Err("the request failed".to_owned())),
+        ];
+
+        assert_eq!(
+            table(&results),
+            "model         first token    chars/s    chars\n\
+             gpt4o-mini          412ms      450.0      900\n\
+             claude3      error: the request failed\n"
+        );
+    }
+}
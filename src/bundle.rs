@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigError, title, ChatHistory, PastChats};
+
+/// Bumped whenever a bundle written by this version can't be read by older ones.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Every saved session in one file, for `--export-bundle` and `--import-bundle`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub sessions: BTreeMap<String, ChatHistory>,
+}
+
+/// How an import went, by session name.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    /// Already present and left alone, for lack of `--overwrite`
+    pub skipped: Vec<String>,
+}
+
+/// Bundles up every readable session in `dir`.
+pub fn export_from(dir: &Path) -> Result<Bundle, ConfigError> {
+    let mut sessions = BTreeMap::new();
+    for session in PastChats::list_in(dir, &title::NoTitle, None)? {
+        if let Some(history) = PastChats::load_session_in(dir, &session.name)? {
+            sessions.insert(session.name, history);
+        }
+    }
+    Ok(Bundle {
+        version: BUNDLE_VERSION,
+        sessions,
+    })
+}
+
+/// Saves the bundled sessions to `dir`, replacing sessions of the same name only if `overwrite`.
+pub fn import_into(
+    dir: &Path,
+    bundle: &Bundle,
+    overwrite: bool,
+) -> Result<ImportReport, ConfigError> {
+    if bundle.version > BUNDLE_VERSION {
+        return Err(ConfigError::Io(std::io::Error::other(format!(
+            "the bundle has version {}, this ddgpt only reads up to {BUNDLE_VERSION}",
+            bundle.version
+        ))));
+    }
+
+    let mut report = ImportReport::default();
+    for (name, history) in &bundle.sessions {
+        if !overwrite && dir.join(name).exists() {
+            report.skipped.push(name.clone());
+            continue;
+        }
+        PastChats::save_in(dir, name, history)?;
+        report.imported.push(name.clone());
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatMessage, ChatRole, GPTModelIdent};
+
+    fn session(content: &str) -> ChatHistory {
+        let messages = vec![ChatMessage {
+            role: ChatRole::User,
+            content: content.to_owned(),
+            truncated: false,
+        }];
+        ChatHistory {
+            next_vqid: "vqid".to_owned(),
+            ..ChatHistory::new(GPTModelIdent::Claude3, messages)
+        }
+    }
+
+    #[test]
+    fn bundle_round_trips_into_a_fresh_data_dir() {
+        let source = tempfile::tempdir().unwrap();
+        PastChats::save_in(source.path(), "first", &session("one")).unwrap();
+        PastChats::save_in(source.path(), "second", &session("two")).unwrap();
+
+        let bundle = export_from(source.path()).unwrap();
+        let bundle: Bundle =
+            serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+        assert_eq!(bundle.version, BUNDLE_VERSION);
+
+        let target = tempfile::tempdir().unwrap();
+        let report = import_into(target.path(), &bundle, false).unwrap();
+        assert_eq!(report.imported, ["first", "second"]);
+        for (name, content) in [("first", "one"), ("second", "two")] {
+            let loaded = PastChats::load_session_in(target.path(), name).unwrap().unwrap();
+            assert_eq!(loaded.chat.messages[0].content, content);
+            assert_eq!(loaded.next_vqid, "vqid");
+        }
+
+        // Collisions are kept unless overwriting
+        PastChats::save_in(target.path(), "first", &session("changed")).unwrap();
+        let report = import_into(target.path(), &bundle, false).unwrap();
+        assert_eq!(report.skipped, ["first", "second"]);
+        let report = import_into(target.path(), &bundle, true).unwrap();
+        assert_eq!(report.imported, ["first", "second"]);
+        let loaded = PastChats::load_session_in(target.path(), "first").unwrap().unwrap();
+        assert_eq!(loaded.chat.messages[0].content, "one");
+    }
+
+    #[test]
+    fn newer_bundle_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = Bundle {
+            version: BUNDLE_VERSION + 1,
+            sessions: BTreeMap::new(),
+        };
+        assert!(import_into(dir.path(), &bundle, false).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    future::Future,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{config, ChatRequest};
+
+/// How long a cached reply is reused, unless `prompt_cache_ttl_secs` says otherwise.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Earlier replies for `--cache`, one file each, named by the hash of the request they answered.
+pub struct PromptCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl PromptCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        PromptCache { dir, ttl }
+    }
+
+    /// Identifies a request by its model and every message, as sent.
+    pub fn key(chat: &ChatRequest) -> String {
+        let serialized = serde_json::to_vec(chat).expect("Failed to json-serialize the request");
+        Sha256::digest(serialized)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// The reply stored under `key`, unless it has outlived the TTL.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let path = self.dir.join(key);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+        if age > self.ttl {
+            return None;
+        }
+        std::fs::read_to_string(path).ok()
+    }
+
+    pub fn put(&self, key: &str, reply: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        config::write_atomic(&self.dir.join(key), reply.as_bytes())
+    }
+
+    /// The cached reply for `key`, or else what `fetch` comes up with, cached if non-empty.
+    /// `fetch` only runs on a miss, or with `refresh`, which skips the lookup.
+    /// Also tells whether the reply came out of the cache.
+    pub async fn reply_through<E>(
+        &self,
+        key: &str,
+        refresh: bool,
+        fetch: impl Future<Output = Result<String, E>>,
+    ) -> (Result<String, E>, bool) {
+        if let Some(reply) = self.get(key).filter(|_| !refresh) {
+            return (Ok(reply), true);
+        }
+
+        let result = fetch.await;
+        match &result {
+            Ok(reply) if !reply.is_empty() => {
+                if let Err(e) = self.put(key, reply) {
+                    eprintln!("Warning: failed to cache the reply: {e}");
+                }
+            }
+            _ => {}
+        }
+        (result, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{ChatMessage, ChatRole, GPTModelIdent};
+
+    fn request(content: &str) -> ChatRequest {
+        ChatRequest {
+            model: GPTModelIdent::GPT4oMini,
+            messages: vec![ChatMessage {
+                role: ChatRole::User,
+                content: content.to_owned(),
+                truncated: false,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_prompt_hits_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PromptCache::new(dir.path().to_owned(), DEFAULT_TTL);
+        let fetches = Cell::new(0);
+        let fetch = |reply: &'static str| async {
+            fetches.set(fetches.get() + 1);
+            Ok::<_, ()>(reply.to_owned())
+        };
+
+        let key = PromptCache::key(&request("what is 2+2?"));
+        assert_eq!(cache.reply_through(&key, false, fetch("4")).await, (Ok("4".into()), false));
+        assert_eq!(cache.reply_through(&key, false, fetch("5")).await, (Ok("4".into()), true));
+        assert_eq!(fetches.get(), 1);
+
+        let changed = PromptCache::key(&request("what is 2+3?"));
+        assert_ne!(changed, key);
+        let (reply, cached) = cache.reply_through(&changed, false, fetch("5")).await;
+        assert_eq!((reply, cached), (Ok("5".into()), false));
+        assert_eq!(fetches.get(), 2);
+
+        // Refreshing asks again and keeps the new reply
+        assert_eq!(cache.reply_through(&key, true, fetch("four")).await.0, Ok("four".into()));
+        assert_eq!(cache.get(&key).as_deref(), Some("four"));
+    }
+
+    #[test]
+    fn expired_reply_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = PromptCache::key(&request("hi"));
+        PromptCache::new(dir.path().to_owned(), DEFAULT_TTL).put(&key, "hello").unwrap();
+
+        let expired = PromptCache::new(dir.path().to_owned(), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(expired.get(&key), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use crate::output::ReplySink;
+
+/// Where pasted text comes from, split out so tests can stand in for the system clipboard.
+pub trait ClipboardSource {
+    fn text(&mut self) -> Result<String, String>;
+}
+
+/// Where copied text goes, the counterpart of [`ClipboardSource`].
+pub trait ClipboardTarget {
+    fn set_text(&mut self, text: &str) -> Result<(), String>;
+}
+
+/// The system clipboard, through `arboard` and the usual command line tools.
+pub struct SystemClipboard;
+
+/// Tried in order once `arboard` couldn't get at the clipboard.
+const PASTE_COMMANDS: &[&[&str]] = &[
+    &["wl-paste", "--no-newline"],
+    &["xclip", "-selection", "clipboard", "-o"],
+    &["xsel", "--clipboard", "--output"],
+    &["pbpaste"],
+    &["powershell", "-NoProfile", "-Command", "Get-Clipboard"],
+];
+
+impl ClipboardSource for SystemClipboard {
+    fn text(&mut self) -> Result<String, String> {
+        let arboard_error = match arboard::Clipboard::new().and_then(|mut c| c.get_text()) {
+            Ok(text) => return Ok(text),
+            Err(e) => e.to_string(),
+        };
+
+        for command in PASTE_COMMANDS {
+            let Ok(output) = Command::new(command[0]).args(&command[1..]).output() else {
+                continue;
+            };
+            if output.status.success() {
+                return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+            }
+        }
+        Err(arboard_error)
+    }
+}
+
+/// Tried in order before `arboard`, whose clipboard contents vanish on X11 and Wayland
+/// as soon as this process exits, unlike those of these tools.
+const COPY_COMMANDS: &[&[&str]] = &[
+    &["wl-copy"],
+    &["xclip", "-selection", "clipboard", "-i"],
+    &["xsel", "--clipboard", "--input"],
+];
+
+impl ClipboardTarget for SystemClipboard {
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        for command in COPY_COMMANDS {
+            let Ok(mut child) = Command::new(command[0])
+                .args(&command[1..])
+                .stdin(Stdio::piped())
+                .spawn()
+            else {
+                continue;
+            };
+            let written = child
+                .stdin
+                .take()
+                .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+            if child.wait().is_ok_and(|status| status.success()) && written {
+                return Ok(());
+            }
+        }
+
+        arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.set_text(text))
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Collects the reply and copies it to the clipboard once complete, for `--copy`.
+pub struct ClipboardSink<C: ClipboardTarget> {
+    target: C,
+    text: String,
+}
+
+impl<C: ClipboardTarget> ClipboardSink<C> {
+    pub fn new(target: C) -> Self {
+        ClipboardSink {
+            target,
+            text: String::new(),
+        }
+    }
+}
+
+impl<C: ClipboardTarget> ReplySink for ClipboardSink<C> {
+    fn name(&self) -> &str {
+        "the clipboard"
+    }
+
+    fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        self.text.push_str(fragment);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+        if self.text.is_empty() {
+            return Ok(());
+        }
+        self.target.set_text(&self.text).map_err(std::io::Error::other)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClipboardError {
+    Unavailable(String),
+    Empty,
+}
+
+impl std::fmt::Display for ClipboardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClipboardError::Unavailable(e) => write!(f, "failed to read the clipboard: {e}"),
+            ClipboardError::Empty => write!(f, "the clipboard holds no text to send"),
+        }
+    }
+}
+
+/// Puts the clipboard's text in front of `query`, which may be empty.
+pub fn prepend_pasted(
+    source: &mut impl ClipboardSource,
+    query: &str,
+) -> Result<String, ClipboardError> {
+    let pasted = source.text().map_err(ClipboardError::Unavailable)?;
+    let pasted = pasted.trim_end();
+    if pasted.trim().is_empty() {
+        return Err(ClipboardError::Empty);
+    }
+
+    Ok(if query.is_empty() {
+        pasted.to_owned()
+    } else {
+        format!("{pasted}\n\n{query}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockClipboard(Result<String, String>);
+
+    impl ClipboardSource for MockClipboard {
+        fn text(&mut self) -> Result<String, String> {
+            self.0.clone()
+        }
+    }
+
+    /// Shares what gets copied with the test, or fails every copy.
+    struct MockTarget(Option<std::rc::Rc<std::cell::RefCell<String>>>);
+
+    impl ClipboardTarget for MockTarget {
+        fn set_text(&mut self, text: &str) -> Result<(), String> {
+            let copied = self.0.as_ref().ok_or("no clipboard")?;
+            *copied.borrow_mut() = text.to_owned();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_sink_gets_the_whole_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reply.md");
+        let file = std::fs::File::create(&path).unwrap();
+        let terminal = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let copied = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+
+        struct SharedVec(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl Write for SharedVec {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut sinks = crate::output::FanOut::default();
+        sinks.push(crate::output::WriterSink::new("stdout", SharedVec(terminal.clone()), false));
+        sinks.push(crate::output::NormalizedWriter::new(file));
+        sinks.push(ClipboardSink::new(MockTarget(Some(copied.clone()))));
+        for fragment in ["Hello", ", ", "world"] {
+            sinks.write_fragment(fragment);
+        }
+        sinks.finish();
+
+        assert_eq!(*terminal.borrow(), b"Hello, world");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Hello, world\n");
+        assert_eq!(*copied.borrow(), "Hello, world");
+    }
+
+    #[test]
+    fn failing_clipboard_leaves_the_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reply.md");
+
+        let mut sinks = crate::output::FanOut::default();
+        sinks.push(ClipboardSink::new(MockTarget(None)));
+        sinks.push(crate::output::NormalizedWriter::new(
+            std::fs::File::create(&path).unwrap(),
+        ));
+        sinks.write_fragment("kept");
+        sinks.finish();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "kept\n");
+    }
+
+    #[test]
+    fn pasted_text_comes_before_the_query() {
+        let mut clipboard = MockClipboard(Ok("fn main() {}\n".to_owned()));
+        assert_eq!(prepend_pasted(&mut clipboard, "").unwrap(), "fn main() {}");
+        assert_eq!(
+            prepend_pasted(&mut clipboard, "what does this do?").unwrap(),
+            "fn main() {}\n\nwhat does this do?"
+        );
+    }
+
+    #[test]
+    fn empty_or_unreadable_clipboard_errors() {
+        let mut clipboard = MockClipboard(Ok(" \n".to_owned()));
+        assert!(matches!(
+            prepend_pasted(&mut clipboard, "hi"),
+            Err(ClipboardError::Empty)
+        ));
+
+        let mut clipboard = MockClipboard(Err("no display".to_owned()));
+        assert!(matches!(
+            prepend_pasted(&mut clipboard, "hi"),
+            Err(ClipboardError::Unavailable(_))
+        ));
+    }
+}
@@ -1,17 +1,19 @@
 use std::{
     error::Error,
-    io::{BufReader, BufWriter},
+    io::{BufReader, Write},
     path::{Path, PathBuf},
 };
 
 macro_rules! templ_fetch_userdir {
     ($fn_name:ident, $XDG_VAR_NAME:literal, $DEFAULT_PATH:literal, $WINDOWS_SUBDIR:literal) => {
-        #[cfg(target_family = "unix")]
+        #[cfg(unix)]
+        #[allow(dead_code)]
         pub fn $fn_name() -> PathBuf {
             user_dir($XDG_VAR_NAME, $DEFAULT_PATH)
         }
 
-        #[cfg(target_os = "windows")]
+        #[cfg(windows)]
+        #[allow(dead_code)]
         pub fn $fn_name() -> PathBuf {
             let mut path = appdata_local_path();
             path.push(env!("CARGO_PKG_NAME"));
@@ -21,12 +23,24 @@ macro_rules! templ_fetch_userdir {
     };
 }
 
-#[cfg(target_family = "unix")]
+#[cfg(unix)]
 fn user_dir(xdg_variable: &'static str, default_user_dir: &'static str) -> PathBuf {
-    let path = std::env::var(xdg_variable)
+    user_dir_from(|name| std::env::var(name).ok(), xdg_variable, default_user_dir)
+}
+
+/// [`user_dir`] with the environment looked up through `var`.
+/// Without the XDG variable or `HOME` there is nowhere persistent to go, so a directory
+/// in the system's temp dir is used instead, warning about it once.
+#[cfg(unix)]
+fn user_dir_from(
+    var: impl Fn(&str) -> Option<String>,
+    xdg_variable: &'static str,
+    default_user_dir: &'static str,
+) -> PathBuf {
+    let path = var(xdg_variable)
         .map(|dir| format!("{}/{}", dir, env!("CARGO_PKG_NAME")))
-        .or_else(|_| {
-            std::env::var("HOME").map(|home_dir| {
+        .or_else(|| {
+            var("HOME").map(|home_dir| {
                 format!(
                     "{}/{}/{}",
                     home_dir,
@@ -36,11 +50,17 @@ fn user_dir(xdg_variable: &'static str, default_user_dir: &'static str) -> PathB
             })
         });
 
-    if let Ok(path) = path {
+    if let Some(path) = path {
         return PathBuf::from(path);
     }
 
-    panic!("User environment did not yield sufficient info to determine config-dir");
+    static WARNING: std::sync::Once = std::sync::Once::new();
+    WARNING.call_once(|| {
+        eprintln!(
+            "Warning: neither {xdg_variable} nor HOME is set, sessions and config won't outlive this system's temp dir"
+        )
+    });
+    std::env::temp_dir().join(env!("CARGO_PKG_NAME"))
 }
 
 #[cfg(windows)]
@@ -71,39 +91,48 @@ fn appdata_local_path() -> PathBuf {
     }).expect("Failed to determine AppData directory!")
 }
 
-
-#[cfg(target_os = "windows")]
-fn user_dir(xdg_variable: &'static str, default_user_dir: &'static str) -> PathBuf {
-    let path = std::env::var(xdg_variable)
-        .map(|dir| format!("{}/{}", dir, env!("CARGO_PKG_NAME")))
-        .or_else(|_| {
-            std::env::var("HOME").map(|home_dir| {
-                format!(
-                    "{}/{}/{}",
-                    home_dir,
-                    default_user_dir,
-                    env!("CARGO_PKG_NAME")
-                )
-            })
-        });
-
-    if let Ok(path) = path {
-        return PathBuf::from(path);
-    }
-
-    panic!("User environment did not yield sufficient info to determine config-dir");
-}
-
 templ_fetch_userdir!(user_cache_dir, "XDG_CACHE_HOME", "/.cache/", "cache");
 templ_fetch_userdir!(user_config_dir, "XDG_CONFIG_HOME", "/.config/", "config");
 templ_fetch_userdir!(user_data_dir, "XDG_DATA_HOME", "/.local/share/", "data");
 templ_fetch_userdir!(user_state_dir, "XDG_STATE_HOME", "/.local/state/", "state");
 
+/// Whether a file can be created in `dir`, found out by creating and removing one.
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".write-check");
+    let writable = std::fs::File::create(&probe).is_ok();
+    if writable {
+        let _ = std::fs::remove_file(&probe);
+    }
+    writable
+}
+
+/// Lists where each of the user directories resolves to and what state it is in, for --paths.
+pub fn paths_report() -> String {
+    let dirs = [
+        ("config", user_config_dir()),
+        ("data", user_data_dir()),
+        ("cache", user_cache_dir()),
+        ("state", user_state_dir()),
+    ];
+
+    let mut report = String::new();
+    for (kind, dir) in dirs {
+        let status = match (dir.is_dir(), dir.is_dir() && is_writable(&dir)) {
+            (false, _) => "missing",
+            (true, true) => "exists, writable",
+            (true, false) => "exists, not writable",
+        };
+        report.push_str(&format!("{kind:<7}{} ({status})\n", dir.display()));
+    }
+    report
+}
+
 // templ_fetch_userdir!(user_cache_dir);
 // templ_fetch_userdir!(user_cache_dir);
 // templ_fetch_userdir!(user_cache_dir);
 // templ_fetch_userdir!(user_cache_dir);
 
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum ConfigFileType {
     // #[cfg( = "JSON")]
     JSON,
@@ -114,6 +143,7 @@ pub enum ConfigFileType {
 pub type ConfigResult<T> = Result<T, ConfigError>;
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ConfigFormatError {
     JSON(serde_json::Error),
     TOML(Box<dyn Error>),
@@ -125,6 +155,24 @@ pub enum ConfigError {
     Format(ConfigFormatError),
 }
 
+impl std::fmt::Display for ConfigFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigFormatError::JSON(e) => write!(f, "invalid json: {e}"),
+            ConfigFormatError::TOML(e) => write!(f, "invalid toml: {e}"),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{e}"),
+            ConfigError::Format(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 impl From<std::io::Error> for ConfigError {
     fn from(value: std::io::Error) -> Self {
         ConfigError::Io(value)
@@ -149,6 +197,39 @@ impl From<toml::de::Error> for ConfigError {
     }
 }
 
+/// Replaces `path` with `contents` without ever leaving a half-written file behind:
+/// the data goes to a temporary file in the same directory which is then renamed over the target.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(path, |tmp_file| tmp_file.write_all(contents))
+}
+
+/// [`write_atomic`] with the temporary file's contents produced by `write`.
+fn write_atomic_with(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("Can't write to a path without a file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = std::fs::File::create(&tmp_path).and_then(|mut tmp_file| {
+        write(&mut tmp_file)?;
+        tmp_file.sync_all()
+    });
+
+    match result.and_then(|_| std::fs::rename(&tmp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            Err(e)
+        }
+    }
+}
+
 fn write_config_file<T: ConfigLoadable>(
     config_path: &std::path::Path,
     config: &T,
@@ -162,13 +243,13 @@ fn write_config_file<T: ConfigLoadable>(
     match T::FILETYPE {
         // #[cfg(predicate)]
         ConfigFileType::JSON => {
-            let writer = BufWriter::new(std::fs::File::create(config_path)?);
-            serde_json::to_writer_pretty(writer, &config)?
+            let serialized = crate::json::to_string(&config, crate::json::JsonStyle::pretty())?;
+            write_atomic(config_path, serialized.as_bytes())?;
         }
 
         // #[cfg(predicate)]
         ConfigFileType::TOML => {
-            std::fs::write(config_path, toml::to_string_pretty(&config)?)?;
+            write_atomic(config_path, toml::to_string_pretty(&config)?.as_bytes())?;
         }
     }
 
@@ -192,30 +273,188 @@ fn load_config_file<T: ConfigLoadable>(config_path: &Path) -> ConfigResult<T> {
 }
 
 
+/// Looks for `filename` in `start` and its parents, up to the enclosing repository's root.
+pub fn find_project_file(start: &Path, filename: &str) -> Option<PathBuf> {
+    for dir in start.ancestors() {
+        let candidate = dir.join(filename);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+/// Overlays the values set in the TOML file at `path` onto `base`,
+/// which keeps everything the file doesn't mention.
+pub fn overlay_config_file<T: ConfigLoadable>(base: T, path: &Path) -> ConfigResult<T> {
+    let mut merged = toml::Table::try_from(&base)?;
+    let overlay: toml::Table = toml::from_str(&std::fs::read_to_string(path)?)?;
+    merge_tables(&mut merged, overlay);
+    Ok(merged.try_into()?)
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// What the config was put back to by [`ConfigLoadable::restore_in`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Restored {
+    Backup,
+    Default,
+}
+
 pub trait ConfigLoadable: Default + serde::Serialize + serde::de::DeserializeOwned {
     const FILENAME: &'static str;
     const FILETYPE: ConfigFileType;
+    /// A TOML file overriding the user config for the project it's found in
+    const PROJECT_FILENAME: Option<&'static str> = None;
 
     fn load() -> ConfigResult<Self> {
-        let mut config_path = user_config_dir();
-        config_path.push(Self::FILENAME);
+        Self::load_in(&user_config_dir())
+    }
+
+    /// [`ConfigLoadable::load`] from `dir` instead of the user's config directory.
+    fn load_in(dir: &Path) -> ConfigResult<Self> {
+        let config_path = dir.join(Self::FILENAME);
 
 
-        let val = match load_config_file(&config_path) {
+        match load_config_file(&config_path) {
             Err(ConfigError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
                 let default_config = Self::default();
                 write_config_file(&config_path, &default_config)?;
-                return Ok(default_config);
+                Ok(default_config)
             }
             val => val,
-        };
+        }
+    }
 
-        val
+    fn restore() -> ConfigResult<Restored> {
+        Self::restore_in(&user_config_dir())
     }
 
+    /// Puts the `<FILENAME>.bak` copy in `dir` back in place if it's a valid config,
+    /// otherwise starts over with the defaults.
+    fn restore_in(dir: &Path) -> ConfigResult<Restored> {
+        let config_path = dir.join(Self::FILENAME);
+        let backup_path = dir.join(format!("{}.bak", Self::FILENAME));
+
+        if load_config_file::<Self>(&backup_path).is_ok() {
+            // Copied as is, keeping its comments and layout
+            write_atomic(&config_path, &std::fs::read(&backup_path)?)?;
+            return Ok(Restored::Backup);
+        }
+        write_config_file(&config_path, &Self::default())?;
+        Ok(Restored::Default)
+    }
+
+    /// [`ConfigLoadable::load`] with the project config found from `start` on top.
+    fn load_for_project(start: &Path) -> ConfigResult<Self> {
+        let config = Self::load()?;
+        match Self::PROJECT_FILENAME.and_then(|filename| find_project_file(start, filename)) {
+            Some(project_path) => overlay_config_file(config, &project_path),
+            None => Ok(config),
+        }
+    }
+
+    #[allow(dead_code)]
     fn save(&self) -> Result<(), ConfigError> {
-        let mut config_path = user_config_dir();
-        config_path.push(Self::FILENAME);
-        write_config_file(&config_path, self)
+        self.save_in(&user_config_dir())
+    }
+
+    fn save_in(&self, dir: &Path) -> Result<(), ConfigError> {
+        write_config_file(&dir.join(Self::FILENAME), self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn missing_home_falls_back_to_temp_dir() {
+        let path = user_dir_from(|_| None, "XDG_DATA_HOME", ".local/share");
+        assert_eq!(path, std::env::temp_dir().join(env!("CARGO_PKG_NAME")));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn xdg_variable_wins_over_home() {
+        let var = |name: &str| match name {
+            "XDG_DATA_HOME" => Some("/xdg".to_owned()),
+            "HOME" => Some("/home/user".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            user_dir_from(var, "XDG_DATA_HOME", ".local/share"),
+            PathBuf::from(concat!("/xdg/", env!("CARGO_PKG_NAME")))
+        );
+        assert_eq!(
+            user_dir_from(
+                |name| var(name).filter(|_| name == "HOME"),
+                "XDG_DATA_HOME",
+                ".local/share"
+            ),
+            PathBuf::from(concat!("/home/user/.local/share/", env!("CARGO_PKG_NAME")))
+        );
+    }
+
+    #[test]
+    fn user_dirs_resolve_on_this_platform() {
+        for dir in [user_config_dir(), user_data_dir()] {
+            assert!(dir.is_absolute(), "{}", dir.display());
+            assert!(dir.components().any(|part| part.as_os_str() == env!("CARGO_PKG_NAME")));
+        }
+    }
+
+    #[test]
+    fn paths_report_lists_every_directory() {
+        let report = paths_report();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 4);
+        for (line, kind) in lines.iter().zip(["config", "data", "cache", "state"]) {
+            let path = line.strip_prefix(kind).unwrap().trim_start();
+            assert!(path.split(" (").next().is_some_and(|path| !path.is_empty()));
+        }
+    }
+
+    #[test]
+    fn failed_write_keeps_the_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        std::fs::write(&path, "good").unwrap();
+
+        let result = write_atomic_with(&path, |tmp_file| {
+            tmp_file.write_all(b"half")?;
+            Err(std::io::Error::other("disk full"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "good");
+        // The temporary file is cleaned up as well
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn successful_write_replaces_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        std::fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
     }
 }
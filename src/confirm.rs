@@ -0,0 +1,97 @@
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmDecision {
+    Proceed,
+    Ask,
+    Abort,
+}
+
+/// Whether the user may be asked anything at all, every question goes through this.
+/// Never with `--yes`, nor without a terminal on stdin to answer from,
+/// so scripts and CI can't end up waiting on input.
+pub fn should_prompt(assume_yes: bool, is_tty: bool) -> bool {
+    !assume_yes && is_tty
+}
+
+/// Decides whether a request needs confirmation before being sent.
+/// Without a TTY to ask on, an explicit `--confirm` aborts unless `--yes` is given,
+/// while a request that merely exceeds the size threshold goes through.
+pub fn confirm_decision(
+    over_threshold: bool,
+    confirm_requested: bool,
+    assume_yes: bool,
+    is_tty: bool,
+) -> ConfirmDecision {
+    if !(over_threshold || confirm_requested) {
+        return ConfirmDecision::Proceed;
+    }
+    if should_prompt(assume_yes, is_tty) {
+        return ConfirmDecision::Ask;
+    }
+
+    // Unasked, only an explicit --confirm without --yes holds the request back
+    if confirm_requested && !assume_yes {
+        ConfirmDecision::Abort
+    } else {
+        ConfirmDecision::Proceed
+    }
+}
+
+/// Asks a yes/no question on stderr, anything but "y"/"yes" counts as no.
+pub fn ask_yes_no(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompts_only_on_a_terminal_without_yes() {
+        assert!(should_prompt(false, true));
+        assert!(!should_prompt(true, true));
+        assert!(!should_prompt(false, false));
+        assert!(!should_prompt(true, false));
+    }
+
+    #[test]
+    fn small_unconfirmed_requests_proceed() {
+        for is_tty in [true, false] {
+            assert_eq!(
+                confirm_decision(false, false, false, is_tty),
+                ConfirmDecision::Proceed
+            );
+        }
+    }
+
+    #[test]
+    fn terminal_gets_asked() {
+        assert_eq!(confirm_decision(true, false, false, true), ConfirmDecision::Ask);
+        assert_eq!(confirm_decision(false, true, false, true), ConfirmDecision::Ask);
+    }
+
+    #[test]
+    fn without_terminal_only_explicit_confirm_aborts() {
+        assert_eq!(confirm_decision(false, true, false, false), ConfirmDecision::Abort);
+        assert_eq!(confirm_decision(true, false, false, false), ConfirmDecision::Proceed);
+    }
+
+    #[test]
+    fn yes_overrides_everything() {
+        for (over_threshold, confirm, is_tty) in [(true, true, true), (true, true, false)] {
+            assert_eq!(
+                confirm_decision(over_threshold, confirm, true, is_tty),
+                ConfirmDecision::Proceed
+            );
+        }
+    }
+}
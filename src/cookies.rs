@@ -0,0 +1,113 @@
+use std::{collections::BTreeMap, path::Path};
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+
+use crate::config::{self, ConfigError};
+
+const FILENAME: &str = "cookies.json";
+
+/// Cookies handed out by the status endpoint, kept around for later chat requests.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CookieJar {
+    cookies: BTreeMap<String, String>,
+}
+
+impl CookieJar {
+    /// Loads the cookies stored in `dir`, a missing or unreadable store counts as empty.
+    pub fn load_in(dir: &Path) -> Self {
+        let cookies = std::fs::read_to_string(dir.join(FILENAME))
+            .ok()
+            .and_then(|stored| serde_json::from_str(&stored).ok())
+            .unwrap_or_default();
+        CookieJar { cookies }
+    }
+
+    pub fn save_in(&self, dir: &Path) -> Result<(), ConfigError> {
+        std::fs::create_dir_all(dir)?;
+        let serialized = serde_json::to_string(&self.cookies)?;
+        config::write_atomic(&dir.join(FILENAME), serialized.as_bytes())?;
+        Ok(())
+    }
+
+    /// Takes over the cookies set by a response, returning whether any of them changed.
+    /// Only names and values are kept, attributes like `Path` or `Expires` are dropped.
+    pub fn capture(&mut self, response_headers: &HeaderMap) -> bool {
+        let mut changed = false;
+        for set_cookie in response_headers.get_all(header::SET_COOKIE) {
+            let Ok(set_cookie) = set_cookie.to_str() else {
+                continue;
+            };
+            let pair = set_cookie.split(';').next().unwrap_or_default();
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+
+            let (name, value) = (name.trim(), value.trim());
+            if !name.is_empty() && self.cookies.get(name).map(String::as_str) != Some(value) {
+                self.cookies.insert(name.to_owned(), value.to_owned());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Adds the stored cookies to the `Cookie` header in `headers`,
+    /// after any cookies already set there.
+    pub fn add_to(&self, headers: &mut HeaderMap) {
+        if self.cookies.is_empty() {
+            return;
+        }
+
+        let stored = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let cookie = match headers.get(header::COOKIE).and_then(|v| v.to_str().ok()) {
+            Some(existing) => format!("{existing}; {stored}"),
+            None => stored,
+        };
+
+        // Captured values came out of a header, so they are valid in one again
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            headers.insert(header::COOKIE, cookie);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured_cookie_is_stored_and_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut status_headers = HeaderMap::new();
+        status_headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("session=abc123; Path=/; HttpOnly"),
+        );
+        status_headers.append(header::SET_COOKIE, HeaderValue::from_static("garbage"));
+        let mut jar = CookieJar::load_in(dir.path());
+        assert!(jar.capture(&status_headers));
+        assert!(!jar.capture(&status_headers));
+        jar.save_in(dir.path()).unwrap();
+
+        // The next run only has what was stored
+        let jar = CookieJar::load_in(dir.path());
+        let mut chat_headers = HeaderMap::new();
+        chat_headers.insert(header::COOKIE, HeaderValue::from_static("dcm=3"));
+        jar.add_to(&mut chat_headers);
+        assert_eq!(chat_headers[header::COOKIE], "dcm=3; session=abc123");
+    }
+
+    #[test]
+    fn empty_jar_leaves_headers_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut headers = HeaderMap::new();
+        CookieJar::load_in(dir.path()).add_to(&mut headers);
+        assert!(headers.is_empty());
+    }
+}
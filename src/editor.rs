@@ -0,0 +1,131 @@
+use std::{io::Write, process::Command};
+
+/// What `--prompt-editor-template` starts the editor with, ahead of any query given.
+pub const TEMPLATE: &str = "\
+# Lines starting with # are left out, and so are empty sections.
+# == instructions ==
+# How the model should answer. There is no system role, so they're sent first, as your words.
+
+# == context ==
+# What the question needs to be understood, like code or an error message.
+
+# == question ==
+";
+
+/// A filled in [`TEMPLATE`], each section trimmed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TemplatePrompt {
+    pub instructions: String,
+    pub context: String,
+    pub question: String,
+}
+
+/// The section name of a `# == NAME ==` marker line.
+fn section_marker(line: &str) -> Option<&str> {
+    let marker = line.trim().strip_prefix('#')?.trim();
+    Some(marker.strip_prefix("==")?.strip_suffix("==")?.trim())
+}
+
+/// Splits a filled in [`TEMPLATE`] into its sections, dropping comment lines.
+/// Text before the first marker counts as part of the question.
+pub fn parse_template(text: &str) -> Result<TemplatePrompt, String> {
+    let mut prompt = TemplatePrompt::default();
+    let mut section = &mut prompt.question;
+    for line in text.lines() {
+        if let Some(name) = section_marker(line) {
+            section = match name {
+                "instructions" => &mut prompt.instructions,
+                "context" => &mut prompt.context,
+                "question" => &mut prompt.question,
+                _ => return Err(format!("unknown template section '{name}'")),
+            };
+            continue;
+        }
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        section.push_str(line);
+        section.push('\n');
+    }
+
+    for section in [&mut prompt.instructions, &mut prompt.context, &mut prompt.question] {
+        *section = section.trim().to_owned();
+    }
+    if prompt.question.is_empty() {
+        return Err("the question section is empty".to_owned());
+    }
+    Ok(prompt)
+}
+
+/// The editor to run for `--edit`, from `$VISUAL`, then `$EDITOR`, or `vi` if both are unset
+/// or blank. The value is split on whitespace, there is no shell to interpret it.
+pub fn editor_command(visual_var: Option<&str>, editor_var: Option<&str>) -> Vec<String> {
+    [visual_var, editor_var]
+        .into_iter()
+        .flatten()
+        .map(|var| var.split_whitespace().map(str::to_owned).collect::<Vec<_>>())
+        .find(|command| !command.is_empty())
+        .unwrap_or_else(|| vec!["vi".to_owned()])
+}
+
+/// Lets the user edit `initial` in their editor, handing back the saved text.
+pub fn edit(initial: &str) -> std::io::Result<String> {
+    let command = editor_command(
+        std::env::var("VISUAL").ok().as_deref(),
+        std::env::var("EDITOR").ok().as_deref(),
+    );
+    // A fresh, unpredictable name, so nothing planted in a shared temp dir gets written through
+    let mut file = tempfile::Builder::new()
+        .prefix("ddgpt-prompt-")
+        .suffix(".md")
+        .tempfile()?;
+    file.write_all(initial.as_bytes())?;
+    file.flush()?;
+
+    let status = Command::new(&command[0]).args(&command[1..]).arg(file.path()).status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("{} exited with {status}", command[0])));
+    }
+    // Removed once `file` is dropped
+    std::fs::read_to_string(file.path())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filled_template_parses_into_its_sections() {
+        let filled = format!(
+            "{}Why does this fail?\n",
+            TEMPLATE
+                .replace("your words.\n", "your words.\nAnswer in one sentence.\n")
+                .replace("error message.\n", "error message.\n  let x: u8 = 256;\n\n")
+        );
+
+        let prompt = parse_template(&filled).unwrap();
+        assert_eq!(
+            prompt,
+            TemplatePrompt {
+                instructions: "Answer in one sentence.".to_owned(),
+                context: "let x: u8 = 256;".to_owned(),
+                question: "Why does this fail?".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn template_needs_a_question() {
+        assert!(parse_template(TEMPLATE).is_err());
+        assert!(parse_template("# == notes ==\nhi").is_err());
+        // Without any markers it's all question
+        assert_eq!(parse_template("# hi\njust ask").unwrap().question, "just ask");
+    }
+
+    #[test]
+    fn editor_falls_back_from_visual_to_editor_to_vi() {
+        assert_eq!(editor_command(Some("code -w"), Some("nano")), ["code", "-w"]);
+        assert_eq!(editor_command(Some(" "), Some("nano")), ["nano"]);
+        assert_eq!(editor_command(None, None), ["vi"]);
+    }
+}
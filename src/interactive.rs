@@ -0,0 +1,321 @@
+use std::{
+    io::{BufRead, Write},
+    time::Instant,
+};
+
+use crate::{
+    confirm_send, held_back_display, output, reply_held_back, report_turn_error, resolve_model,
+    send_turn, stored_session_name, title, ChatHistory, PastChats, TurnContext, DIMMED,
+};
+
+const HELP: &str = "\
+/stats          show turn count, characters sent/received and session duration
+/model NAME     switch to another model
+/save NAME      save the session under NAME (and keep saving there)
+/clear          start over with an empty conversation
+/quit           leave (as does Ctrl-D)";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplCommand {
+    Stats,
+    Model(String),
+    Save(String),
+    Clear,
+    Help,
+    Quit,
+    Unknown(String),
+}
+
+/// Parses a `/command` line, regular chat input yields `None`.
+pub fn parse_command(line: &str) -> Option<ReplCommand> {
+    let command = line.trim().strip_prefix('/')?;
+    let (name, argument) = command
+        .split_once(char::is_whitespace)
+        .map(|(name, argument)| (name, argument.trim()))
+        .unwrap_or((command, ""));
+
+    Some(match (name, argument) {
+        ("stats", "") => ReplCommand::Stats,
+        ("model", model) if !model.is_empty() => ReplCommand::Model(model.to_owned()),
+        ("save", name) if !name.is_empty() => ReplCommand::Save(name.to_owned()),
+        ("clear", "") => ReplCommand::Clear,
+        ("help", "") => ReplCommand::Help,
+        ("quit" | "exit", "") => ReplCommand::Quit,
+        _ => ReplCommand::Unknown(line.trim().to_owned()),
+    })
+}
+
+/// Reads the next turn from `input`: a single line, or with a `multiline` sentinel
+/// every line up to one holding just the sentinel (or the end of input).
+/// `None` once the input is exhausted.
+pub fn read_turn(
+    input: &mut impl BufRead,
+    multiline: Option<&str>,
+) -> std::io::Result<Option<String>> {
+    let mut turn: Option<String> = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(turn);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        let Some(sentinel) = multiline else {
+            return Ok(Some(line.to_owned()));
+        };
+        if line == sentinel {
+            return Ok(Some(turn.unwrap_or_default()));
+        }
+        match turn.as_mut() {
+            Some(turn) => {
+                turn.push('\n');
+                turn.push_str(line);
+            }
+            None => turn = Some(line.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionStats {
+    turns: usize,
+    chars_sent: usize,
+    chars_received: usize,
+    started: Instant,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        SessionStats {
+            turns: 0,
+            chars_sent: 0,
+            chars_received: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn record_turn(&mut self, sent: &str, received: &str) {
+        self.turns += 1;
+        self.chars_sent += sent.chars().count();
+        self.chars_received += received.chars().count();
+    }
+
+    pub fn report(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs();
+        format!(
+            "{} turn(s), {} chars sent, {} chars received, running for {}:{:02}:{:02}",
+            self.turns,
+            self.chars_sent,
+            self.chars_received,
+            elapsed / 3600,
+            elapsed / 60 % 60,
+            elapsed % 60
+        )
+    }
+}
+
+/// Runs the read-eval-print loop until stdin closes or `/quit`.
+/// With a `multiline` sentinel, turns span lines until one holding just the sentinel.
+pub async fn run(
+    ctx: &TurnContext<'_>,
+    mut chat_history: ChatHistory,
+    mut session_name: Option<String>,
+    first_query: Option<String>,
+    multiline: Option<&str>,
+) {
+    let mut stats = SessionStats::new();
+    let mut pending_query = first_query;
+
+    if let Some(sentinel) = multiline {
+        anstream::eprintln!(
+            "{DIMMED}End each turn with a line holding just '{sentinel}', or Ctrl-D{DIMMED:#}"
+        );
+    }
+
+    loop {
+        let query = match pending_query.take() {
+            Some(query) => query,
+            None => {
+                eprint!("> ");
+                let _ = std::io::stderr().flush();
+                // Not holding on to the stdin lock, the --confirm question reads from it as well
+                match read_turn(&mut std::io::stdin().lock(), multiline) {
+                    Ok(None) => break,
+                    Ok(Some(turn)) => turn,
+                    Err(e) => {
+                        eprintln!("Error: failed to read input: {e}");
+                        break;
+                    }
+                }
+            }
+        };
+
+        if query.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(command) = parse_command(&query) {
+            match command {
+                ReplCommand::Stats => anstream::eprintln!("{DIMMED}{}{DIMMED:#}", stats.report()),
+                ReplCommand::Model(name) => match resolve_model(&name) {
+                    Ok(model_arg) => {
+                        chat_history.chat.model = model_arg.to_model();
+                        anstream::eprintln!(
+                            "{DIMMED}Using model: {}{DIMMED:#}",
+                            model_arg.as_str()
+                        );
+                    }
+                    Err(_) => eprintln!("Unknown or ambiguous model '{name}'"),
+                },
+                ReplCommand::Save(name) => {
+                    let name = stored_session_name(&name, ctx.normalize_session_case);
+                    match PastChats::save(&name, &chat_history) {
+                        Ok(()) => {
+                            anstream::eprintln!("{DIMMED}Saved as {name}{DIMMED:#}");
+                            session_name = Some(name);
+                        }
+                        Err(e) => eprintln!("Error: failed to save the chat: {e}"),
+                    }
+                }
+                ReplCommand::Clear => {
+                    // A fresh conversation, with a fresh vqid, and nothing of the old one
+                    // left pinned or titled
+                    chat_history = ChatHistory::new(chat_history.chat.model, Vec::new());
+                    anstream::eprintln!("{DIMMED}Conversation cleared{DIMMED:#}");
+                }
+                ReplCommand::Help => eprintln!("{HELP}"),
+                ReplCommand::Quit => break,
+                ReplCommand::Unknown(line) => {
+                    eprintln!("Unknown command '{line}', try /help")
+                }
+            }
+            continue;
+        }
+
+        if !confirm_send(ctx, query.len(), chat_history.chat.model) {
+            continue;
+        }
+        if ctx.args.echo {
+            // Part of the transcript, unlike the "> " prompt
+            let _ = output::echo_prompt(&mut std::io::stdout(), &query);
+        }
+        let mut on_fragment = |fragment: &str| {
+            if !reply_held_back(ctx.args) {
+                print!("{fragment}");
+                let _ = std::io::stdout().flush();
+            }
+        };
+
+        let turn = send_turn(ctx, &mut chat_history, query.clone(), &mut on_fragment).await;
+        let assistant_message = match turn {
+            Ok(assistant_message) => assistant_message,
+            Err(e) => {
+                // send_turn kept whatever part of the reply arrived
+                let kept = e.partial_reply().is_some();
+                report_turn_error(ctx.args, &e, kept);
+                if kept {
+                    if let Err(e) = PastChats::save_to(
+                        &mut session_name,
+                        &mut chat_history,
+                        ctx.max_session_bytes,
+                    ) {
+                        eprintln!("Error: failed to save the chat: {e}");
+                    }
+                }
+                continue;
+            }
+        };
+
+        if reply_held_back(ctx.args) {
+            print!("{}", held_back_display(ctx.args, &assistant_message));
+        }
+        println!();
+
+        if assistant_message.is_empty() {
+            continue;
+        }
+
+        stats.record_turn(&query, &assistant_message);
+
+        title::title_if_missing(ctx, &mut chat_history).await;
+        if let Err(e) =
+            PastChats::save_to(&mut session_name, &mut chat_history, ctx.max_session_bytes)
+        {
+            eprintln!("Error: failed to save the chat: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_map_to_actions() {
+        assert_eq!(parse_command("/stats"), Some(ReplCommand::Stats));
+        assert_eq!(
+            parse_command("  /model  claude3 "),
+            Some(ReplCommand::Model("claude3".to_owned()))
+        );
+        assert_eq!(
+            parse_command("/save notes"),
+            Some(ReplCommand::Save("notes".to_owned()))
+        );
+        assert_eq!(parse_command("/clear"), Some(ReplCommand::Clear));
+        assert_eq!(parse_command("/help"), Some(ReplCommand::Help));
+        assert_eq!(parse_command("/quit"), Some(ReplCommand::Quit));
+        assert_eq!(parse_command("/exit"), Some(ReplCommand::Quit));
+    }
+
+    #[test]
+    fn malformed_commands_are_unknown() {
+        for line in ["/model", "/save  ", "/stats now", "/frobnicate"] {
+            assert_eq!(
+                parse_command(line),
+                Some(ReplCommand::Unknown(line.trim().to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn chat_input_is_not_a_command() {
+        assert_eq!(parse_command("what is /etc/hosts?"), None);
+        assert_eq!(parse_command(""), None);
+    }
+
+    #[test]
+    fn multiline_turns_end_at_the_sentinel() {
+        let mut input = "fn main() {\r\n    println!(\"hi\");\n}\n.\nnext\n.\n".as_bytes();
+        let sentinel = Some(".");
+        assert_eq!(
+            read_turn(&mut input, sentinel).unwrap().as_deref(),
+            Some("fn main() {\n    println!(\"hi\");\n}")
+        );
+        assert_eq!(read_turn(&mut input, sentinel).unwrap().as_deref(), Some("next"));
+        assert_eq!(read_turn(&mut input, sentinel).unwrap(), None);
+
+        // The end of input submits what's there, a custom sentinel works the same
+        let mut input = "a\nEND\nb\nc".as_bytes();
+        assert_eq!(read_turn(&mut input, Some("END")).unwrap().as_deref(), Some("a"));
+        assert_eq!(read_turn(&mut input, Some("END")).unwrap().as_deref(), Some("b\nc"));
+    }
+
+    #[test]
+    fn single_line_turns_without_multiline() {
+        let mut input = "one\ntwo\n".as_bytes();
+        assert_eq!(read_turn(&mut input, None).unwrap().as_deref(), Some("one"));
+        assert_eq!(read_turn(&mut input, None).unwrap().as_deref(), Some("two"));
+        assert_eq!(read_turn(&mut input, None).unwrap(), None);
+    }
+
+    #[test]
+    fn stats_count_turns_and_chars() {
+        let mut stats = SessionStats::new();
+        stats.record_turn("hi", "hello ä");
+        stats.record_turn("bye", "");
+        assert!(stats
+            .report()
+            .starts_with("2 turn(s), 5 chars sent, 7 chars received, running for 0:00:0"));
+    }
+}
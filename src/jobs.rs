@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, future::Future};
+
+use futures_util::{stream, StreamExt};
+
+/// Runs `jobs` with at most `limit` of them going at once, handing each output to `on_output`
+/// in the order the jobs came in, as soon as it and every one before it are done.
+pub async fn run_in_order<F: Future>(
+    jobs: impl IntoIterator<Item = F>,
+    limit: usize,
+    mut on_output: impl FnMut(F::Output),
+) {
+    let mut finished = stream::iter(jobs.into_iter().enumerate())
+        .map(|(index, job)| async move { (index, job.await) })
+        .buffer_unordered(limit.max(1));
+
+    // Outputs finished ahead of their turn wait here, by index
+    let mut waiting = BTreeMap::new();
+    let mut next = 0;
+    while let Some((index, output)) = finished.next().await {
+        waiting.insert(index, output);
+        while let Some(output) = waiting.remove(&next) {
+            on_output(output);
+            next += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, time::Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn outputs_keep_the_input_order() {
+        let completed = RefCell::new(Vec::new());
+        let job = |name: &'static str, ms| {
+            let completed = &completed;
+            async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                completed.borrow_mut().push(name);
+                name
+            }
+        };
+
+        let mut outputs = Vec::new();
+        let jobs = [job("slow", 60), job("fast", 5), job("medium", 30)];
+        run_in_order(jobs, 3, |name| outputs.push(name)).await;
+
+        assert_eq!(*completed.borrow(), ["fast", "medium", "slow"]);
+        assert_eq!(outputs, ["slow", "fast", "medium"]);
+    }
+
+    #[tokio::test]
+    async fn limit_of_one_runs_them_one_after_the_other() {
+        let completed = RefCell::new(Vec::new());
+        let job = |name: &'static str, ms| {
+            let completed = &completed;
+            async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                completed.borrow_mut().push(name);
+            }
+        };
+
+        run_in_order([job("slow", 30), job("fast", 5)], 1, |()| {}).await;
+        assert_eq!(*completed.borrow(), ["slow", "fast"]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::OnceLock;
+
+use serde::Serialize;
+
+/// The layout `--json-compact` or `json_indent` asks for, unset if neither did.
+pub static CONFIGURED: OnceLock<JsonStyle> = OnceLock::new();
+
+/// How JSON output is laid out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonStyle {
+    /// All on one line
+    Compact,
+    /// A line per value, indented with the given string per level
+    Indented(String),
+}
+
+impl JsonStyle {
+    /// What `serde_json` pretty-prints with.
+    pub fn pretty() -> Self {
+        JsonStyle::Indented("  ".to_owned())
+    }
+
+    /// The style asked for, `--json-compact` winning over `json_indent`, an empty one of which
+    /// means compact as well.
+    pub fn requested(compact: bool, indent: Option<&str>) -> Option<Self> {
+        if compact {
+            return Some(JsonStyle::Compact);
+        }
+        indent.map(|indent| match indent {
+            "" => JsonStyle::Compact,
+            indent => JsonStyle::Indented(indent.to_owned()),
+        })
+    }
+
+    pub fn to_string<T: Serialize + ?Sized>(&self, value: &T) -> serde_json::Result<String> {
+        match self {
+            JsonStyle::Compact => serde_json::to_string(value),
+            JsonStyle::Indented(indent) => {
+                let mut out = Vec::new();
+                let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+                value.serialize(&mut serde_json::Serializer::with_formatter(&mut out, formatter))?;
+                Ok(String::from_utf8(out).expect("serde_json only writes UTF-8"))
+            }
+        }
+    }
+}
+
+/// `value` in the configured style, or in `unset` where none was asked for,
+/// so each output keeps its usual layout by default.
+pub fn to_string<T: Serialize + ?Sized>(value: &T, unset: JsonStyle) -> serde_json::Result<String> {
+    CONFIGURED.get().unwrap_or(&unset).to_string(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_lay_out_the_same_value() {
+        let value = serde_json::json!({ "name": "notes", "tags": ["a"] });
+
+        assert_eq!(
+            JsonStyle::Compact.to_string(&value).unwrap(),
+            r#"{"name":"notes","tags":["a"]}"#
+        );
+        assert_eq!(
+            JsonStyle::pretty().to_string(&value).unwrap(),
+            serde_json::to_string_pretty(&value).unwrap()
+        );
+        assert_eq!(
+            JsonStyle::Indented("\t".to_owned()).to_string(&value).unwrap(),
+            "{\n\t\"name\": \"notes\",\n\t\"tags\": [\n\t\t\"a\"\n\t]\n}"
+        );
+    }
+
+    #[test]
+    fn compact_flag_wins_over_the_indent() {
+        assert_eq!(JsonStyle::requested(true, Some("\t")), Some(JsonStyle::Compact));
+        assert_eq!(JsonStyle::requested(false, Some("")), Some(JsonStyle::Compact));
+        assert_eq!(
+            JsonStyle::requested(false, Some("    ")),
+            Some(JsonStyle::Indented("    ".to_owned()))
+        );
+        assert_eq!(JsonStyle::requested(false, None), None);
+    }
+}
@@ -1,54 +1,569 @@
-use std::{fmt::Debug, io::Write};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{BufWriter, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 use clap::{
-    builder::{styling::Style, TypedValueParser},
+    builder::{
+        styling::{AnsiColor, Reset, Style},
+        TypedValueParser,
+    },
     error::{ContextKind, ContextValue, ErrorKind},
-    Parser, ValueEnum,
+    parser::ValueSource,
+    CommandFactory, FromArgMatches, ValueEnum,
 };
 use config::{ConfigError, ConfigLoadable};
+use json::JsonStyle;
 // use eventsource::reqwest::Client;
-use reqwest::{header, Client, Url};
+use reqwest::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Client, Url,
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use title::TitleStrategy;
 
+mod benchmark;
+mod bundle;
+mod cache;
+mod clipboard;
 mod config;
+mod confirm;
+mod cookies;
+mod editor;
+mod interactive;
+mod jobs;
+mod json;
+mod output;
+mod pacing;
+mod preflight;
+mod prompt;
+mod redact;
+mod title;
+mod tui;
 const DIMMED: Style = Style::new().dimmed();
+const WARNING: Style = AnsiColor::Red.on_default().bold();
+const VQD_HEADER: &str = "x-vqd-4";
+const MAX_RESUME_ATTEMPTS: usize = 3;
+const RESUME_PROMPT: &str = "Your previous reply was cut off. Continue it exactly where it stopped, without repeating anything.";
 
 #[derive(Debug, clap::Parser)]
-#[command(about = "A CLI interface to duckduckgo's chatbots")]
+#[command(version, about = "A CLI interface to duckduckgo's chatbots")]
 struct Cli {
-    #[arg(short = 'm', long = "model", value_parser=ModelIdentArgParser())]
+    /// The model to chat with, a trailing '!' picks the closest match even when it's ambiguous
+    #[arg(short = 'm', long = "model", value_parser=ModelIdentArgParser::configured())]
     model: Option<ModelIdentArg>,
+    /// Whether `model` came from the command line itself, rather than `DDGPT_MODEL`
+    #[arg(skip)]
+    model_passed: bool,
 
     #[arg(short = 's', long = "session")]
     session_name: Option<String>,
     #[arg(short = 'c', long = "continue")]
     continue_session: bool,
-    #[arg(short = 'i', long = "interactive")]
+    /// Like --continue, but start the named session fresh if it doesn't exist yet
+    #[arg(long = "continue-if-exists", conflicts_with_all = ["continue_session", "context_from"])]
+    continue_if_exists: bool,
+    /// Drop the last N turns of the continued session before asking
+    #[arg(long = "rewind", value_name = "N", requires = "continue_session")]
+    rewind: Option<usize>,
+    /// Save the finished conversation as session NAME, even with --no-append,
+    /// leaving any session it continued untouched
+    #[arg(long = "save-as", visible_alias = "branch-as", value_name = "NAME")]
+    save_as: Option<String>,
+    /// Let --rewind overwrite the continued session, dropping its last turns for good
+    #[arg(long = "in-place", requires = "rewind", conflicts_with = "save_as")]
+    in_place: bool,
+    /// Keep chatting, one turn per line (see /help)
+    #[arg(short = 'i', long = "interactive", conflicts_with_all = ["output", "no_append"])]
     interactive_session: bool,
+    /// In --interactive, let turns span lines until one holding just "." (or Ctrl-D)
+    #[arg(long = "multiline", requires = "interactive_session")]
+    multiline: bool,
+    /// Keep chatting in a full-screen interface with a scrollable transcript
+    #[arg(
+        long = "tui",
+        conflicts_with_all = ["interactive_session", "output", "no_append"]
+    )]
+    tui: bool,
+    #[arg(short = 'v', long = "verbose")]
+    verbose: bool,
+    /// Leave out the context size and session count warnings and the --trace-timing report
+    #[arg(short = 'q', long = "quiet")]
+    quiet: bool,
+    /// Report how long the handshake, the first token and the whole stream took
+    #[arg(long = "trace-timing")]
+    trace_timing: bool,
+    /// Write every raw event of the reply stream to FILE, separated by blank lines as in the stream
+    #[arg(long = "dump-events", value_name = "FILE")]
+    dump_events: Option<PathBuf>,
+    /// Show the reply in a --dump-events FILE as if it had just arrived, without any request
+    #[arg(
+        long = "replay-events",
+        value_name = "FILE",
+        conflicts_with_all = ["interactive_session", "tui", "dump_events"]
+    )]
+    replay_events: Option<PathBuf>,
+    /// Write every event of the reply stream to stdout as it arrives, as json lines,
+    /// instead of the reply
+    #[arg(
+        long = "ndjson-events",
+        visible_alias = "stream-json-events",
+        conflicts_with_all = [
+            "interactive_session",
+            "tui",
+            "pager",
+            "reply_only_to_file",
+            "compare_with"
+        ]
+    )]
+    ndjson_events: bool,
+
+    /// How to combine multiple query arguments
+    #[arg(long = "join", value_name = "SEPARATOR", default_value = "space")]
+    join: QueryJoin,
+    /// Strip the indentation all lines of the assembled prompt have in common
+    #[arg(long = "dedent")]
+    dedent: bool,
+
+    /// Append a file's content to the query as a fenced block (repeatable)
+    #[arg(short = 'f', long = "include-file", value_name = "PATH")]
+    include_files: Vec<PathBuf>,
+    /// The encoding included files are in, like latin1 or shift_jis, instead of UTF-8
+    #[arg(long = "input-encoding", value_name = "LABEL", value_parser = parse_encoding)]
+    input_encoding: Option<&'static encoding_rs::Encoding>,
+    /// Include every file listed (one path per line) in PATH, or stdin for '-'
+    #[arg(long = "files-from", value_name = "PATH|-")]
+    files_from: Option<PathBuf>,
+    /// Start a new conversation with FILE's plain text as a single message
+    #[arg(long = "context-from", value_name = "FILE", conflicts_with = "continue_session")]
+    context_from: Option<PathBuf>,
+    /// Start a new conversation by telling the model today's date
+    #[arg(long = "with-date")]
+    with_date: bool,
+    /// Who the --context-from message is attributed to
+    #[arg(
+        long = "context-role",
+        value_name = "ROLE",
+        default_value = "user",
+        requires = "context_from"
+    )]
+    context_role: ChatRole,
+
+    /// Print the reply as plain text, once complete, with markdown syntax removed
+    #[arg(long = "strip-markdown")]
+    strip_markdown: bool,
+    /// After the reply, show how it differs from the last reply in session NAME
+    #[arg(
+        long = "compare-with",
+        value_name = "NAME",
+        conflicts_with_all = ["interactive_session", "tui"]
+    )]
+    compare_with: Option<String>,
+    /// Show the reply once complete, without a trailing "Sources:" or references section
+    #[arg(long = "no-sources")]
+    no_sources: bool,
+    /// Turn escape sequences like \n in the reply into the characters they stand for,
+    /// before printing it once complete and saving it
+    #[arg(long = "unescape")]
+    unescape: bool,
+
+    /// Also write the reply to FILE, with normalized line endings
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Stream the reply only to the --output file, showing a spinner and where it went instead
+    #[arg(long = "reply-only-to-file", requires = "output", conflicts_with = "pager")]
+    reply_only_to_file: bool,
+
+    /// Send this turn with the session as context, but keep it out of the saved history
+    #[arg(long = "no-append")]
+    no_append: bool,
+
+    /// Also copy the reply to the clipboard once it's complete
+    #[arg(long = "copy", conflicts_with_all = ["interactive_session", "tui"])]
+    copy: bool,
+
+    /// Exit with status 3 if the reply has no content
+    #[arg(long = "fail-on-empty", conflicts_with_all = ["interactive_session", "tui"])]
+    fail_on_empty: bool,
+    /// Exit with status 4 if the reply starts with one of the refusal_phrases
+    #[arg(long = "strict-refusals", conflicts_with_all = ["interactive_session", "tui"])]
+    strict_refusals: bool,
+
+    /// Leave stdout ending wherever the reply does, instead of on exactly one newline
+    #[arg(long = "no-trailing-newline")]
+    no_trailing_newline: bool,
+
+    /// Page the reply through $PAGER (or less -R) as it streams in, if stdout is a terminal
+    #[arg(long = "pager", conflicts_with_all = ["interactive_session", "tui"])]
+    pager: bool,
+
+    /// Print each prompt, labeled, before sending it (to stderr for one-shot queries)
+    #[arg(long = "echo", conflicts_with = "tui")]
+    echo: bool,
+
+    /// Reuse the reply to an identical earlier prompt if it's cached, and cache new replies
+    #[arg(
+        long = "cache",
+        visible_alias = "prompt-cache",
+        conflicts_with_all = ["interactive_session", "tui"]
+    )]
+    cache: bool,
+    /// Ask even if --cache holds a reply, and cache the new one instead
+    #[arg(long = "refresh", requires = "cache")]
+    refresh: bool,
+
+    /// If the connection drops mid-reply, ask the model to continue where it stopped
+    #[arg(long = "resume-on-drop")]
+    resume_on_drop: bool,
+
+    /// Ask for confirmation before sending the request
+    #[arg(long = "confirm")]
+    confirm: bool,
+    /// Show the messages about to be sent with their size and estimated tokens, then ask
+    /// like --confirm
+    #[arg(long = "preview", conflicts_with_all = ["interactive_session", "tui"])]
+    preview: bool,
+    /// Answer yes to any confirmation
+    #[arg(short = 'y', long = "yes")]
+    assume_yes: bool,
+
+    /// Fail right away if duckduckgo.com can't be reached, instead of waiting for a timeout
+    #[arg(long = "probe")]
+    probe: bool,
+
+    /// Give up connecting after SECS seconds, overriding connect_timeout_secs
+    #[arg(long = "connect-timeout", value_name = "SECS")]
+    connect_timeout: Option<u64>,
+    /// Give up on a reply once nothing arrived for SECS seconds, overriding read_timeout_secs
+    #[arg(long = "read-timeout", value_name = "SECS")]
+    read_timeout: Option<u64>,
+
+    /// Skip TLS certificate verification (UNSAFE, only for debugging intercepting proxies)
+    #[arg(long = "insecure")]
+    insecure: bool,
+
+    /// Add or override a header on the chat request, as "NAME: VALUE" (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_extra_header)]
+    extra_headers: Vec<ExtraHeader>,
+    /// Allow --header to override the x-vqd-4 session header
+    #[arg(long = "force", requires = "extra_headers")]
+    force: bool,
+    /// Send VALUE as the Authorization header on every request, overriding auth_header
+    #[arg(long = "auth", value_name = "VALUE", value_parser = parse_auth)]
+    auth: Option<HeaderValue>,
+    /// Send TOKEN as the x-vqd-4 ID and skip the status request, to replay a captured one
+    #[arg(
+        long = "vqid",
+        value_name = "TOKEN",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    vqid: Option<String>,
+
+    /// List the saved sessions and exit
+    #[arg(long = "list-sessions")]
+    list_sessions: bool,
+    /// Only list the sessions whose name or messages match REGEX
+    #[arg(long = "grep", value_name = "REGEX", requires = "list_sessions")]
+    grep: Option<regex::Regex>,
+    /// Write every saved session to FILE, as one json bundle, and exit
+    #[arg(long = "export-bundle", value_name = "FILE")]
+    export_bundle: Option<PathBuf>,
+    /// Save the sessions in the bundle FILE, skipping any already present, and exit
+    #[arg(long = "import-bundle", value_name = "FILE", conflicts_with = "export_bundle")]
+    import_bundle: Option<PathBuf>,
+    /// Let --import-bundle replace sessions of the same name
+    #[arg(long = "overwrite", requires = "import_bundle")]
+    overwrite: bool,
+    /// Emit json instead of human readable text (for --list-sessions and --fail-on-empty)
+    #[arg(long = "json")]
+    json: bool,
+    /// Put all json output, saved sessions included, on a single line, overriding json_indent
+    #[arg(long = "json-compact")]
+    json_compact: bool,
+    /// Show timestamps as RFC3339 in UTC instead of local time
+    #[arg(long = "utc")]
+    utc: bool,
+
+    /// Print name, version and supported models as json and exit
+    #[arg(long = "version-json")]
+    version_json: bool,
+    /// Send PROMPT to every model and compare how fast they reply, without saving anything
+    #[arg(long = "benchmark", value_name = "PROMPT")]
+    benchmark: Option<String>,
+    /// Only benchmark this model (repeatable)
+    #[arg(
+        long = "benchmark-model",
+        value_name = "NAME",
+        value_parser = ModelIdentArgParser::configured(),
+        requires = "benchmark"
+    )]
+    benchmark_models: Vec<ModelIdentArg>,
+    /// After the reply, ask MODEL the same and print its answer too, without saving it
+    /// (repeatable)
+    #[arg(
+        long = "also-ask",
+        value_name = "MODEL",
+        value_parser = ModelIdentArgParser::configured(),
+        conflicts_with_all = ["interactive_session", "tui", "ndjson_events"]
+    )]
+    also_ask: Vec<ModelIdentArg>,
+    /// Run up to N of the requests of --benchmark or --also-ask at once, overriding max_concurrent
+    #[arg(
+        long = "jobs",
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    jobs: Option<usize>,
+    /// Ask the model for a summary of session NAME and print it, without changing the session
+    #[arg(long = "summarize-session", value_name = "NAME")]
+    summarize_session: Option<String>,
+    /// Print the messages of session NAME, numbered from 0, and exit
+    #[arg(long = "show", value_name = "NAME")]
+    show: Option<String>,
+    /// Make --show start at message INDEX
+    #[arg(long = "from", value_name = "INDEX", requires = "show")]
+    show_from: Option<usize>,
+    /// Make --show stop after message INDEX
+    #[arg(long = "to", value_name = "INDEX", requires = "show")]
+    show_to: Option<usize>,
+    /// Print a model's CLI name, aliases and API identifier and exit
+    #[arg(
+        long = "model-info",
+        value_name = "NAME",
+        value_parser = ModelIdentArgParser::configured()
+    )]
+    model_info: Option<ModelIdentArg>,
+    /// Print every model as a tab separated line of CLI name, aliases and API identifier and exit
+    #[arg(long = "list-models")]
+    list_models: bool,
+    /// Put config.toml.bak back as the config, or the defaults if it's unusable, and exit
+    #[arg(long = "restore-config")]
+    restore_config: bool,
+    /// Print where config, sessions, cache and state are kept and exit
+    #[arg(long = "paths")]
+    paths: bool,
+    /// Print the JSON Schema of the on-disk session or config format and exit
+    #[arg(long = "json-schema", value_name = "KIND")]
+    json_schema: Option<SchemaKind>,
+
+    /// The query as a single argument, taken literally even if it starts with '-'
+    #[arg(
+        long = "prompt",
+        value_name = "TEXT",
+        allow_hyphen_values = true,
+        conflicts_with = "query"
+    )]
+    prompt: Option<String>,
+    /// Start the query with the clipboard's text, any query arguments follow it
+    #[arg(long = "paste", visible_alias = "from-clipboard")]
+    paste: bool,
+    /// Compose the query in $VISUAL or $EDITOR, starting from any query given
+    #[arg(long = "edit", conflicts_with_all = ["interactive_session", "tui"])]
+    edit: bool,
+    /// Start --edit from a template with instructions, context and question sections
+    #[arg(long = "prompt-editor-template", requires = "edit")]
+    prompt_editor_template: bool,
+    /// Send JSON, an array of {"role", "content"} messages, as the whole conversation
+    #[arg(
+        long = "messages-json",
+        value_name = "JSON",
+        value_parser = parse_messages_json,
+        conflicts_with_all = [
+            "query",
+            "prompt",
+            "paste",
+            "edit",
+            "include_files",
+            "files_from",
+            "context_from",
+            "with_date",
+            "continue_session",
+            "continue_if_exists",
+            "interactive_session",
+            "tui",
+            "model_from_prompt"
+        ]
+    )]
+    messages_json: Option<ScriptedMessages>,
+    /// Pick the model with a first prompt line like "@claude3", which isn't sent
+    #[arg(long = "model-from-prompt")]
+    model_from_prompt: bool,
 
     #[arg(
         // last = true,
         // multiple = true,
         trailing_var_arg=true,
-        required = true
+        required_unless_present_any = [
+            "version_json",
+            "model_info",
+            "list_models",
+            "benchmark",
+            "summarize_session",
+            "show",
+            "paths",
+            "restore_config",
+            "json_schema",
+            "list_sessions",
+            "export_bundle",
+            "import_bundle",
+            "interactive_session",
+            "tui",
+            "prompt",
+            "paste",
+            "edit",
+            "messages_json",
+            "replay_events"
+        ]
     )]
     query: Vec<String>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Prefixes the environment variables standing in for absent options.
+const ENV_PREFIX: &str = "DDGPT_";
+
+impl Cli {
+    /// The command line, every option falling back to an environment variable named after it,
+    /// like `DDGPT_MODEL` for `--model` or `DDGPT_NO_APPEND` for `--no-append`.
+    /// A flag given wins over the variable, which wins over the config.
+    fn command_with_env() -> clap::Command {
+        Cli::command().mut_args(|arg| match arg.get_long() {
+            Some(long) if !matches!(long, "help" | "version") => {
+                let var = format!("{ENV_PREFIX}{}", long.to_uppercase().replace('-', "_"));
+                // Values like --auth's aren't for --help to show
+                arg.env(var).hide_env_values(true)
+            }
+            _ => arg,
+        })
+    }
+
+    fn parse_with_env() -> Self {
+        Cli::from_matches_with_env(&Cli::command_with_env().get_matches())
+            .unwrap_or_else(|e| e.exit())
+    }
+
+    /// The options [`Cli::command_with_env`] matched, noting where the model came from.
+    fn from_matches_with_env(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        let mut cli = Cli::from_arg_matches(matches)?;
+        cli.model_passed = matches.value_source("model") == Some(ValueSource::CommandLine);
+        Ok(cli)
+    }
+
+    /// The model given with `-m`, which unlike one from `DDGPT_MODEL` counts as picked explicitly.
+    fn explicit_model(&self) -> Option<ModelIdentArg> {
+        self.model.filter(|_| self.model_passed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum QueryJoin {
+    Space,
+    Newline,
+}
+
+impl QueryJoin {
+    fn separator(self) -> &'static str {
+        match self {
+            QueryJoin::Space => " ",
+            QueryJoin::Newline => "\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum SchemaKind {
+    Session,
+    Config,
+}
+
+#[derive(Debug, Clone)]
+struct ExtraHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+fn parse_extra_header(raw: &str) -> Result<ExtraHeader, String> {
+    let (name, value) = raw
+        .split_once(':')
+        .ok_or_else(|| "expected a header of the form \"NAME: VALUE\"".to_owned())?;
+
+    Ok(ExtraHeader {
+        name: HeaderName::try_from(name.trim()).map_err(|e| format!("invalid header name: {e}"))?,
+        value: HeaderValue::try_from(value.trim())
+            .map_err(|e| format!("invalid header value: {e}"))?,
+    })
+}
+
+fn parse_auth(raw: &str) -> Result<HeaderValue, String> {
+    let mut value =
+        HeaderValue::try_from(raw.trim()).map_err(|e| format!("invalid header value: {e}"))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+fn parse_encoding(label: &str) -> Result<&'static encoding_rs::Encoding, String> {
+    encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| format!("unknown encoding '{label}'"))
+}
+
+/// The whole conversation to send, given as a JSON array of messages.
+#[derive(Debug, Clone)]
+struct ScriptedMessages(Vec<ChatMessage>);
+
+fn parse_messages_json(raw: &str) -> Result<ScriptedMessages, String> {
+    let messages: Vec<ChatMessage> =
+        serde_json::from_str(raw).map_err(|e| format!("invalid messages: {e}"))?;
+    match messages.last() {
+        None => Err("expected at least one message".to_owned()),
+        Some(last) if last.role != ChatRole::User => {
+            Err("the last message has to be the user's, for the model to reply to".to_owned())
+        }
+        Some(_) => Ok(ScriptedMessages(messages)),
+    }
+}
+
+/// The session this run saves to, if named on the command line.
+/// A rewound session is only overwritten when asked to.
+fn save_target(args: &Cli) -> Result<Option<&str>, clap::Error> {
+    if args.rewind.is_some() && !args.no_append && !args.in_place && args.save_as.is_none() {
+        return Err(Cli::command().error(
+            ErrorKind::MissingRequiredArgument,
+            "--rewind drops turns from the saved session, pass --save-as <NAME> to keep the original or --in-place to overwrite it",
+        ));
+    }
+
+    Ok(args.save_as.as_deref().or(args.session_name.as_deref()))
+}
+
+fn extra_header_map(args: &Cli) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for ExtraHeader { name, value } in &args.extra_headers {
+        if name == VQD_HEADER && !args.force {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("overriding the {VQD_HEADER} header breaks the session, pass --force if you really mean it"),
+                )
+                .exit();
+        }
+        headers.insert(name.clone(), value.clone());
+    }
+    headers
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
 enum ModelIdentArg {
+    #[default]
     GPT4oMini,
     Claude3,
     Llama3,
     Mixtral,
 }
 
-impl Default for ModelIdentArg {
-    fn default() -> Self {
-        ModelIdentArg::GPT4oMini
-    }
-}
-
 impl ModelIdentArg {
     fn as_str(&self) -> &'static str {
         match self {
@@ -59,7 +574,7 @@ impl ModelIdentArg {
         }
     }
 
-    fn to_model(&self) -> GPTModelIdent {
+    fn to_model(self) -> GPTModelIdent {
         match self {
             ModelIdentArg::GPT4oMini => GPTModelIdent::GPT4oMini,
             ModelIdentArg::Claude3 => GPTModelIdent::Claude3,
@@ -69,9 +584,20 @@ impl ModelIdentArg {
     }
 }
 
-impl Into<&'static str> for &ModelIdentArg {
-    fn into(self) -> &'static str {
-        self.as_str()
+impl From<GPTModelIdent> for ModelIdentArg {
+    fn from(value: GPTModelIdent) -> Self {
+        match value {
+            GPTModelIdent::GPT4oMini => ModelIdentArg::GPT4oMini,
+            GPTModelIdent::Claude3 => ModelIdentArg::Claude3,
+            GPTModelIdent::Llama3 => ModelIdentArg::Llama3,
+            GPTModelIdent::Mixtral => ModelIdentArg::Mixtral,
+        }
+    }
+}
+
+impl From<&ModelIdentArg> for &'static str {
+    fn from(value: &ModelIdentArg) -> Self {
+        value.as_str()
     }
 }
 
@@ -102,15 +628,15 @@ fn rank_aliases<T>(v: &str) -> Vec<(f64, T)>
 where
     T: ValueEnum + Clone + Debug,
 {
-    let mut candidates: Vec<_> = T::value_variants().into_iter()
+    let mut candidates: Vec<_> = T::value_variants().iter()
         // GH #4660: using `jaro` because `jaro_winkler` implementation in `strsim-rs` is wrong
         // causing strings with common prefix >=10 to be considered perfectly similar
         .map(|variant: &T| {
             let pmatch = variant.to_possible_value().expect("ValueEnum::value_variants contains only values with a corresponding ValueEnum::to_possible_value");
             (pmatch.get_name_and_aliases().map(| alias | {
-                // let res = strsim::jaro(v, alias.as_ref());
+                // let res = strsim::jaro(v, alias);
                 // println!("{alias}, {res}");
-                strsim::jaro(v, alias.as_ref())
+                strsim::jaro(v, alias)
             }).fold(0.0f64, |acc, v| acc.max(v)), variant.clone())
         })
         .collect();
@@ -119,8 +645,37 @@ where
     candidates
 }
 
+/// How closely a mistyped model name has to match to be accepted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ModelMatching {
+    /// The least similarity the best match needs
+    threshold: f64,
+    /// How far the best match has to be ahead of the runner-up
+    margin: f64,
+}
+
+impl Default for ModelMatching {
+    fn default() -> Self {
+        ModelMatching {
+            threshold: 0.8,
+            margin: 0.1,
+        }
+    }
+}
+
+/// Set from the config before the arguments are parsed.
+static MODEL_MATCHING: std::sync::OnceLock<ModelMatching> = std::sync::OnceLock::new();
+
 #[derive(Debug, Clone, Copy)]
-struct ModelIdentArgParser();
+struct ModelIdentArgParser(ModelMatching);
+
+impl ModelIdentArgParser {
+    /// Matches the way the config asks for, or the default way if it hasn't been loaded.
+    fn configured() -> Self {
+        ModelIdentArgParser(MODEL_MATCHING.get().copied().unwrap_or_default())
+    }
+}
+
 impl TypedValueParser for ModelIdentArgParser {
     type Value = ModelIdentArg;
     fn parse_ref(
@@ -156,6 +711,12 @@ impl TypedValueParser for ModelIdentArgParser {
             value = value.to_lowercase();
         }
 
+        // A trailing '!' accepts the best match, however close the runner-up is
+        let forced = value.ends_with('!');
+        if forced {
+            value.pop();
+        }
+
         let err_val = || {
             invalid_value(
                 cmd,
@@ -166,18 +727,29 @@ impl TypedValueParser for ModelIdentArgParser {
             )
         };
 
+        // Declared names and aliases are never ambiguous, however close the others score
+        if let Ok(exact) = Self::Value::from_str(&value, true) {
+            return Ok(exact);
+        }
+
         let mut candidates = rank_aliases::<Self::Value>(&value);
 
         // dbg!(&candidates);
         let (best_score, best_match) = candidates
             .pop()
-            .filter(|(score, _val)| *score > 0.8)
-            .ok_or_else(|| err_val())?;
+            .filter(|(score, _val)| *score > self.0.threshold)
+            .ok_or_else(err_val)?;
 
         let second_best_score = candidates.pop().map(|a| a.0).unwrap_or(0.0);
 
-        if best_score - second_best_score < 0.1 {
-            return Err(err_val());
+        if best_score - second_best_score < self.0.margin {
+            if !forced {
+                return Err(err_val());
+            }
+            anstream::eprintln!(
+                "{DIMMED}Forced the ambiguous model '{value}' to {}{DIMMED:#}",
+                best_match.as_str()
+            );
         }
 
         Ok(best_match)
@@ -230,20 +802,74 @@ pub(crate) fn invalid_value(
     err
 }
 
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// How large a single event may grow, unless `max_event_bytes` says otherwise.
+const DEFAULT_MAX_EVENT_BYTES: usize = 16 * 1024 * 1024;
+
+/// More than the limit was buffered without a delimiter showing up.
+#[derive(Debug, PartialEq, Eq)]
+struct EventTooLarge(usize);
+
 struct ChunkParser<'a> {
     buf: Vec<u8>,
     delim: &'a [u8],
+    at_stream_start: bool,
+    /// How much of an unfinished event may be buffered
+    max_buf: usize,
 }
 
 impl<'a> ChunkParser<'a> {
-    fn update(&mut self, new_bytes: &[u8]) -> Vec<Vec<u8>> {
-        let start = 0isize.max(self.buf.len() as isize - self.delim.len() as isize + 1);
+    fn new(delim: &'a [u8]) -> Self {
+        ChunkParser {
+            buf: vec![],
+            delim,
+            at_stream_start: true,
+            max_buf: DEFAULT_MAX_EVENT_BYTES,
+        }
+    }
+
+    fn with_max_buf(self, max_buf: usize) -> Self {
+        ChunkParser { max_buf, ..self }
+    }
+
+    /// Some proxies prepend a BOM or blank lines to the stream,
+    /// which would otherwise end up glued to the first event.
+    fn strip_preamble(&mut self) {
+        // The BOM itself may be split across chunks
+        if self.buf.len() < UTF8_BOM.len() && UTF8_BOM.starts_with(&self.buf) {
+            return;
+        }
+
+        let bom_len = if self.buf.starts_with(UTF8_BOM) {
+            UTF8_BOM.len()
+        } else {
+            0
+        };
+        let blank_len = self.buf[bom_len..]
+            .iter()
+            .take_while(|b| matches!(b, b'\r' | b'\n'))
+            .count();
+        self.buf.drain(..bom_len + blank_len);
+
+        self.at_stream_start = self.buf.is_empty();
+    }
+
+    /// Splits off the events `new_bytes` completes. Fails once the unfinished rest
+    /// grows past the limit, leaving the stream beyond saving.
+    fn update(&mut self, new_bytes: &[u8]) -> Result<Vec<Vec<u8>>, EventTooLarge> {
+        let mut start = 0isize.max(self.buf.len() as isize - self.delim.len() as isize + 1);
         self.buf.extend_from_slice(new_bytes);
 
+        if self.at_stream_start {
+            self.strip_preamble();
+            start = 0;
+        }
+
         let mut idx: usize = start as usize;
         let mut result = Vec::new();
 
-        while idx <= (self.buf.len() - self.delim.len()) {
+        while idx + self.delim.len() <= self.buf.len() {
             if &self.buf[idx..idx + self.delim.len()] != self.delim {
                 idx += 1;
                 continue;
@@ -261,316 +887,4838 @@ impl<'a> ChunkParser<'a> {
             }
         }
 
-        result
+        if self.buf.len() > self.max_buf {
+            self.buf.clear();
+            return Err(EventTooLarge(self.max_buf));
+        }
+        Ok(result)
+    }
+
+    /// The last event, once the stream has ended without a delimiter after it.
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        let rest = std::mem::take(&mut self.buf);
+        let len = rest.len() - rest.iter().rev().take_while(|b| b.is_ascii_whitespace()).count();
+        (len > 0).then(|| rest[..len].to_vec())
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
 struct DDGPTConfigDescription {
     default_chatbot: ModelIdentArg,
+    /// Ask before sending prompts larger than this many bytes
+    confirm_above_bytes: Option<usize>,
+    /// Archive a session and start it over once it grows past this many bytes
+    max_session_bytes: Option<usize>,
+    /// Keep the cookies the status endpoint sets and send them along with chat requests
+    use_cookies: bool,
+    /// Check that DDG is reachable before sending anything, like --probe
+    preflight_probe: bool,
+    /// How chats started without a session name get saved
+    unnamed_session_strategy: UnnamedSessionStrategy,
+    /// Tell new conversations today's date, like --with-date
+    inject_datetime: bool,
+    /// Join adjacent messages of the same role before sending them (the default),
+    /// false refuses to send such a conversation instead
+    merge_consecutive_roles: Option<bool>,
+    /// Make the model last picked with -m the new default_chatbot
+    remember_last_model: bool,
+    /// How similar a mistyped model name has to be to a known one, 0.8 by default
+    model_match_threshold: Option<f64>,
+    /// How much closer than the runner-up the best match has to be, 0.1 by default
+    model_match_margin: Option<f64>,
+    /// Warn once more sessions than this are saved, 1000 by default
+    max_sessions: Option<usize>,
+    /// The line ending a turn under --multiline, "." by default
+    multiline_sentinel: Option<String>,
+    /// The least time between two chat requests of one run, 200 by default
+    min_request_interval_ms: Option<u64>,
+    /// How many requests of --benchmark or --also-ask may run at once, 1 by default
+    max_concurrent: Option<usize>,
+    /// Lowercase session names, so names differing in case only are the same session everywhere
+    normalize_session_case: bool,
+    /// How sessions are titled in --list-sessions: first_message, model_summary or none
+    title_strategy: title::TitleStrategyKind,
+    /// Sent as the Authorization header on every request, nothing by default
+    auth_header: Option<String>,
+    /// How long --cache reuses a reply, in seconds, a day by default
+    prompt_cache_ttl_secs: Option<u64>,
+    /// Regexes whose matches are stored as [REDACTED] in saved sessions, though still sent
+    redact_patterns: Vec<String>,
+    /// How long connecting may take, in seconds, no limit by default
+    connect_timeout_secs: Option<u64>,
+    /// How long a reply may stall, in seconds, no limit by default
+    read_timeout_secs: Option<u64>,
+    /// How replies that --strict-refusals fails on start, ignoring case,
+    /// a few common ones by default
+    refusal_phrases: Option<Vec<String>>,
+    /// Warn when a reply is identical to one of the session's last 32, a sign the model is stuck
+    detect_repetition: bool,
+    /// The largest single event accepted from the chatbot, 16 MiB by default
+    max_event_bytes: Option<usize>,
+    /// Sent ahead of every new conversation with the model, as your words since there's no
+    /// system role, like `Claude3 = "Answer briefly."`
+    model_system_prefix: HashMap<ModelIdentArg, String>,
+    /// The indent of all json output, saved sessions included, like "\t", or "" for a single line.
+    /// Unset, listings and schemas are indented by two spaces and the rest is on a single line
+    json_indent: Option<String>,
+}
+
+impl DDGPTConfigDescription {
+    /// What model_system_prefix opens new conversations with `model` with.
+    fn system_prefix(&self, model: ModelIdentArg) -> Option<&str> {
+        self.model_system_prefix
+            .get(&model)
+            .map(String::as_str)
+            .filter(|prefix| !prefix.trim().is_empty())
+    }
+
+    fn model_matching(&self) -> ModelMatching {
+        let default = ModelMatching::default();
+        ModelMatching {
+            threshold: self.model_match_threshold.unwrap_or(default.threshold),
+            margin: self.model_match_margin.unwrap_or(default.margin),
+        }
+    }
+}
+
+const DEFAULT_MAX_SESSIONS: usize = 1000;
+const DEFAULT_MULTILINE_SENTINEL: &str = ".";
+
+/// Makes an explicitly picked model the default in the config in `dir`, if `enabled`.
+/// Only that file is touched, project overrides stay out of it.
+/// Returns whether the default changed.
+fn remember_model_in(
+    dir: &Path,
+    enabled: bool,
+    explicit: Option<ModelIdentArg>,
+) -> Result<bool, ConfigError> {
+    let Some(model) = explicit.filter(|_| enabled) else {
+        return Ok(false);
+    };
+
+    let mut user_config = DDGPTConfigDescription::load_in(dir)?;
+    if user_config.default_chatbot == model {
+        return Ok(false);
+    }
+    user_config.default_chatbot = model;
+    user_config.save_in(dir)?;
+    Ok(true)
+}
+
+/// [`remember_model_in`] for the user's config, warning instead of failing the run.
+fn remember_model(enabled: bool, explicit: Option<ModelIdentArg>) {
+    if let Err(e) = remember_model_in(&config::user_config_dir(), enabled, explicit) {
+        eprintln!("Warning: failed to remember the model as the new default: {e}");
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum UnnamedSessionStrategy {
+    /// A new session per run, named after when it was saved
+    #[default]
+    Timestamp,
+    /// One session per day that every unnamed run appends to
+    Daily,
+    /// A single session every unnamed run appends to
+    Fixed,
+}
+
+impl UnnamedSessionStrategy {
+    /// The name unnamed chats get at `now`.
+    /// For [`UnnamedSessionStrategy::Timestamp`] this is only the start,
+    /// saving adds a suffix to keep runs apart.
+    fn session_name<Tz: chrono::TimeZone>(self, now: &chrono::DateTime<Tz>) -> String
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        match self {
+            UnnamedSessionStrategy::Timestamp => now.format("%Y%m%d-%H%M%S").to_string(),
+            UnnamedSessionStrategy::Daily => now.format("daily-%Y-%m-%d").to_string(),
+            UnnamedSessionStrategy::Fixed => "unnamed".to_owned(),
+        }
+    }
+
+    /// The session unnamed runs share at `now`, if they share one at all.
+    fn shared_session<Tz: chrono::TimeZone>(self, now: &chrono::DateTime<Tz>) -> Option<String>
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        (self != UnnamedSessionStrategy::Timestamp).then(|| self.session_name(now))
+    }
 }
 
 impl ConfigLoadable for DDGPTConfigDescription {
     const FILENAME: &'static str = "config.toml";
     const FILETYPE: config::ConfigFileType = config::ConfigFileType::TOML;
+    const PROJECT_FILENAME: Option<&'static str> = Some(".ddgpt.toml");
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
 enum ChatRole {
     Assistant,
     User,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl ChatRole {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::Assistant => "assistant",
+            ChatRole::User => "user",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct ChatMessage {
     role: ChatRole,
     content: String,
+    /// Set on a reply cut off before the model finished it, like by --max-chars
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct ChatRequest {
     model: GPTModelIdent,
     messages: Vec<ChatMessage>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct ChatHistory {
     chat: ChatRequest,
     next_vqid: String,
+    /// How many leading messages (like --context-from) survive the session being rotated
+    #[serde(default)]
+    pinned: usize,
+    /// The model DDG reported serving the last reply with, which may differ from the requested one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    served_model: Option<String>,
+    /// Set by the model_summary title strategy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /// Hashes of the latest replies, oldest first, kept with detect_repetition
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reply_hashes: Vec<String>,
 }
 
-struct PastChats {}
-impl PastChats {
-    fn load_last() -> Result<Option<ChatHistory>, ConfigError> {
-        let data_path = config::user_data_dir();
-        let dir_iter = match std::fs::read_dir(&data_path) {
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                std::fs::create_dir_all(&data_path)?;
-                return Ok(None);
-            }
-            res => res,
-        }?;
+/// How many of the latest replies `detect_repetition` compares a new one against.
+const REPETITION_WINDOW: usize = 32;
 
-        let mut last_chat = None;
-        for entry_result in dir_iter {
-            let entry = entry_result?;
-            let entry_time = entry.metadata()?.modified()?;
-            match last_chat {
-                Some((_, last_chat_time)) if last_chat_time > entry_time => continue,
-                _ => last_chat = Some((entry, entry_time)),
-            }
+impl ChatHistory {
+    /// A fresh conversation with `model`, nothing pinned and no vqid yet.
+    fn new(model: GPTModelIdent, messages: Vec<ChatMessage>) -> Self {
+        ChatHistory {
+            chat: ChatRequest { model, messages },
+            next_vqid: String::new(),
+            pinned: 0,
+            served_model: None,
+            title: None,
+            reply_hashes: Vec::new(),
         }
-
-        Ok(match last_chat {
-            Some((val, _)) => Some(serde_json::from_str(&std::fs::read_to_string(val.path())?)?),
-            None => None,
-        })
     }
 
-    fn load_session_from_name(name: &str) -> Result<Option<ChatHistory>, ConfigError> {
-        if name.contains("/") || name.contains(".") {
-            return Err(ConfigError::Io(std::io::Error::other(
-                "Invalid session name!",
-            )));
+    /// Remembers a hash of `reply`, telling whether it matches one of the replies remembered
+    /// before, leading and trailing whitespace aside.
+    fn note_reply(&mut self, reply: &str) -> bool {
+        let hash: String = Sha256::digest(reply.trim())
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let repeated = self.reply_hashes.contains(&hash);
+        self.reply_hashes.push(hash);
+        if self.reply_hashes.len() > REPETITION_WINDOW {
+            self.reply_hashes.remove(0);
         }
+        repeated
+    }
+}
 
-        let mut data_path = config::user_data_dir();
-        data_path.push(name);
+/// Warns if `reply` repeats an earlier one, with detect_repetition.
+fn warn_on_repetition(ctx: &TurnContext<'_>, chat_history: &mut ChatHistory, reply: &str) {
+    if ctx.detect_repetition && chat_history.note_reply(reply) {
+        eprintln!(
+            "Warning: the reply is identical to an earlier one, the model may be stuck. \
+             Rephrasing the prompt or starting a new session usually helps."
+        );
+    }
+}
 
-        let data = match std::fs::read_to_string(&data_path) {
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
-            val => val,
-        }?;
+#[derive(Debug, Serialize)]
+struct SessionInfo {
+    name: String,
+    /// Last modification, in seconds since the unix epoch
+    updated: u64,
+    message_count: usize,
+    model: &'static str,
+    title: String,
+}
 
-        Ok(Some(serde_json::from_str(&data)?))
-    }
+const SUMMARY_INSTRUCTION: &str = "Summarize this conversation:";
+/// Roughly what fits a request, older messages are left out beyond it
+const SUMMARY_MAX_CHARS: usize = 24_000;
 
-    fn save(name: &str, chat: &ChatHistory) -> Result<(), ConfigError> {
-        if name.contains("/") || name.contains(".") {
-            return Err(ConfigError::Io(std::io::Error::other(
-                "Invalid session name!",
-            )));
+/// Renders messages as plain text, one labeled paragraph each.
+fn transcript(messages: &[ChatMessage]) -> String {
+    messages
+        .iter()
+        .map(|message| format!("{}: {}", message.role.as_str(), message.content.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The request asking for a summary of `messages`, keeping as many of the latest ones
+/// as fit in `max_chars`. Should even the last one not fit, only its end is kept.
+fn summary_prompt(messages: &[ChatMessage], max_chars: usize) -> String {
+    let mut kept = messages.len();
+    let mut kept_chars = 0;
+    while kept > 0 {
+        let message_chars = transcript(&messages[kept - 1..kept]).chars().count() + 2;
+        if kept_chars + message_chars > max_chars {
+            break;
         }
+        kept_chars += message_chars;
+        kept -= 1;
+    }
 
-        let mut data_path = config::user_data_dir();
-        // Ensure the directory exists!
-        std::fs::create_dir_all(&data_path)?;
-        data_path.push(name);
+    let mut text = transcript(&messages[kept..]);
+    if kept == messages.len() {
+        if let Some(last) = messages.last() {
+            let last = transcript(std::slice::from_ref(last));
+            let skip = last.chars().count().saturating_sub(max_chars);
+            text = last.chars().skip(skip).collect();
+            kept -= 1;
+        }
+    }
 
-        let chat_serialized = serde_json::to_string(chat)?;
-        std::fs::write(data_path, chat_serialized)?;
+    let omitted = match kept {
+        0 => String::new(),
+        kept => format!("[{kept} earlier message(s) left out]\n\n"),
+    };
+    format!("{SUMMARY_INSTRUCTION}\n\n{omitted}{text}")
+}
 
-        Ok(())
+/// The messages as sent, with runs of the same role joined into one message
+/// since some models reject them. Without `merge` the first such run is an error.
+fn merge_consecutive_roles(
+    messages: &[ChatMessage],
+    merge: bool,
+) -> Result<Vec<ChatMessage>, ChatRole> {
+    let mut merged: Vec<ChatMessage> = Vec::with_capacity(messages.len());
+    for message in messages {
+        match merged.last_mut() {
+            Some(last) if last.role == message.role => {
+                if !merge {
+                    return Err(message.role);
+                }
+                last.content.push('\n');
+                last.content.push_str(&message.content);
+            }
+            // The flag is for the session only, DDG gets the plain message
+            _ => merged.push(ChatMessage {
+                truncated: false,
+                ..message.clone()
+            }),
+        }
     }
+    Ok(merged)
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct ChatBotEvent {
-    action: String,
-    created: u64,
-    message: Option<String>,
+/// Removes the last `turns` user turns (each with whatever replies followed it).
+/// The first `pinned` messages are never dropped, whatever their role, while replies
+/// left without a user turn before them are, so the request never opens on one.
+/// Returns how many turns were actually dropped.
+fn truncate_turns(messages: &mut Vec<ChatMessage>, pinned: usize, turns: usize) -> usize {
+    let mut dropped = 0;
+    while dropped < turns {
+        let pinned = pinned.min(messages.len());
+        match messages[pinned..]
+            .iter()
+            .rposition(|message| matches!(message.role, ChatRole::User))
+        {
+            Some(idx) => messages.truncate(pinned + idx),
+            None => break,
+        }
+        dropped += 1;
+    }
 
-    // Doesn't exist for claude3
-    id: Option<String>,
-    model: Option<String>,
+    let pinned = pinned.min(messages.len());
+    if dropped > 0 && !messages[pinned..].iter().any(|m| matches!(m.role, ChatRole::User)) {
+        messages.truncate(pinned);
+    }
+    dropped
+}
 
-    // Nonexistent for everything but claude3
-    role: Option<String>,
+/// Adds the reply to the history, `truncated` if it was cut off, or with `no_append` puts back
+/// the messages as they were loaded, keeping only the fresh vqid.
+fn record_reply(
+    chat_history: &mut ChatHistory,
+    loaded_messages: Vec<ChatMessage>,
+    reply: String,
+    truncated: bool,
+    no_append: bool,
+) {
+    if no_append {
+        chat_history.chat.messages = loaded_messages;
+    } else {
+        chat_history.chat.messages.push(ChatMessage {
+            role: ChatRole::Assistant,
+            content: reply,
+            truncated,
+        });
+    }
 }
 
-#[derive(
-    Debug,
-    serde::Deserialize,
-    serde::Serialize, // clap::ValueEnum, Clone, Copy, PartialEq, Eq,
-)]
-enum GPTModelIdent {
-    #[serde(rename = "gpt-4o-mini")]
-    GPT4oMini,
-    #[serde(rename = "claude-3-haiku-20240307")]
-    Claude3,
-    #[serde(rename = "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo")]
-    Llama3,
-    #[serde(rename = "mistralai/Mixtral-8x7B-Instruct-v0.1")]
-    Mixtral,
+/// The name a session is stored under, lowercased with `normalize_case`
+/// so it doesn't matter whether the filesystem tells case apart.
+fn stored_session_name(name: &str, normalize_case: bool) -> String {
+    if normalize_case {
+        name.to_lowercase()
+    } else {
+        name.to_owned()
+    }
 }
 
-fn display_message_fragment(assistant_message: &mut String, message_buffer: &[u8]) {
-    let message = match message_buffer.strip_prefix(b"data: ") {
-        Some(message) => message,
-        None => return,
+/// Applies [`stored_session_name`] to every session named on the command line.
+fn normalize_session_names(args: &mut Cli, normalize_case: bool) {
+    let names = [
+        &mut args.session_name,
+        &mut args.save_as,
+        &mut args.summarize_session,
+        &mut args.show,
+        &mut args.compare_with,
+    ];
+    for name in names.into_iter().flatten() {
+        *name = stored_session_name(name, normalize_case);
+    }
+}
+
+/// The messages `--from` and `--to` pick out of `len`, both inclusive,
+/// clamped to the session with a warning if they reach past it.
+fn message_range(
+    len: usize,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> (std::ops::Range<usize>, Option<String>) {
+    if len == 0 {
+        return (0..0, None);
+    }
+
+    let last = len - 1;
+    let mut clamped = Vec::new();
+    let mut clamp = |flag: &str, index: Option<usize>, default: usize| match index {
+        Some(index) if index > last => {
+            clamped.push(format!(
+                "Warning: {flag} {index} is past the last message, {last}, using that instead"
+            ));
+            last
+        }
+        index => index.unwrap_or(default),
+    };
+    let (from, to) = (clamp("--from", from, 0), clamp("--to", to, last));
+
+    let warning = (!clamped.is_empty()).then(|| clamped.join("\n"));
+    (from..(to + 1).max(from), warning)
+}
+
+/// Like [`transcript`], with every message labeled by its index in the session.
+fn numbered_transcript(messages: &[ChatMessage], first_index: usize) -> String {
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, message)| {
+            let index = first_index + i;
+            let marker = if message.truncated { " [truncated]" } else { "" };
+            format!(
+                "[{index}] {}: {}{marker}",
+                message.role.as_str(),
+                message.content.trim()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn show_session(name: &str, from: Option<usize>, to: Option<usize>) {
+    let session = PastChats::load_session_from_name(name)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to load session {name}: {e}");
+            std::process::exit(1);
+        })
+        .unwrap_or_else(|| {
+            eprintln!("Error: there is no session named {name}");
+            std::process::exit(1);
+        });
+
+    let messages = &session.chat.messages;
+    let (range, warning) = message_range(messages.len(), from, to);
+    if let Some(warning) = warning {
+        eprintln!("{warning}");
+    }
+    println!("{}", numbered_transcript(&messages[range.clone()], range.start));
+}
+
+struct PastChats {}
+impl PastChats {
+    /// Gathers metadata on every readable session, most recently updated first.
+    fn list(
+        titles: &dyn TitleStrategy,
+        grep: Option<&regex::Regex>,
+    ) -> Result<Vec<SessionInfo>, ConfigError> {
+        Self::list_in(&config::user_data_dir(), titles, grep)
+    }
+
+    /// Counts the sessions in `dir` by name alone, without reading any of them.
+    fn count_in(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| !entry.file_name().to_string_lossy().contains('.'))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Like [`PastChats::list`], keeping only the sessions `grep` finds in the name or a message.
+    fn list_in(
+        dir: &Path,
+        titles: &dyn TitleStrategy,
+        grep: Option<&regex::Regex>,
+    ) -> Result<Vec<SessionInfo>, ConfigError> {
+        let dir_iter = match std::fs::read_dir(dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            res => res,
+        }?;
+
+        let mut sessions = Vec::new();
+        for entry_result in dir_iter {
+            let entry = entry_result?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.contains('.') {
+                continue;
+            }
+
+            // Not ours to judge if unreadable or unparseable, but not a session either
+            let Ok(contents) = std::fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            let Ok(history) = serde_json::from_str::<ChatHistory>(&contents) else {
+                continue;
+            };
+            if let Some(grep) = grep {
+                let mut messages = history.chat.messages.iter();
+                if !grep.is_match(&name) && !messages.any(|m| grep.is_match(&m.content)) {
+                    continue;
+                }
+            }
+
+            let updated = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|age| age.as_secs())
+                .unwrap_or(0);
+
+            sessions.push(SessionInfo {
+                title: titles.title(&history).unwrap_or_default(),
+                name,
+                updated,
+                message_count: history.chat.messages.len(),
+                model: ModelIdentArg::from(history.chat.model).as_str(),
+            });
+        }
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.updated));
+        Ok(sessions)
+    }
+
+    /// Loads the most recently updated session along with its name,
+    /// the name being what saving it back in place takes.
+    fn load_last_in(data_path: &Path) -> Result<Option<(String, ChatHistory)>, ConfigError> {
+        let dir_iter = match std::fs::read_dir(data_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir_all(data_path)?;
+                return Ok(None);
+            }
+            res => res,
+        }?;
+
+        let mut last_chat = None;
+        for entry_result in dir_iter {
+            let entry = entry_result?;
+            // Session names never contain a '.', unlike leftover temporary files
+            if entry.file_name().to_string_lossy().contains('.') {
+                continue;
+            }
+            let entry_time = entry.metadata()?.modified()?;
+            match last_chat {
+                Some((_, last_chat_time)) if last_chat_time > entry_time => continue,
+                _ => last_chat = Some((entry, entry_time)),
+            }
+        }
+
+        Ok(match last_chat {
+            Some((val, _)) => Some((
+                val.file_name().to_string_lossy().into_owned(),
+                serde_json::from_str(&std::fs::read_to_string(val.path())?)?,
+            )),
+            None => None,
+        })
+    }
+
+    fn load_continued(
+        name: Option<&str>,
+        soft: bool,
+    ) -> Result<Option<(String, ChatHistory)>, ConfigError> {
+        Self::load_continued_in(&config::user_data_dir(), name, soft)
+    }
+
+    /// Loads the session `--continue` picks up along with its name: `name` if given,
+    /// or else the most recently updated one. A missing named session is an error,
+    /// unless `soft` (for `--continue-if-exists`) where it's there to be started.
+    fn load_continued_in(
+        dir: &Path,
+        name: Option<&str>,
+        soft: bool,
+    ) -> Result<Option<(String, ChatHistory)>, ConfigError> {
+        let Some(name) = name else {
+            return Self::load_last_in(dir);
+        };
+
+        match Self::load_session_in(dir, name)? {
+            Some(history) => Ok(Some((name.to_owned(), history))),
+            None if soft => Ok(None),
+            None => Err(ConfigError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("there is no session named '{name}' to continue"),
+            ))),
+        }
+    }
+
+    fn load_session_from_name(name: &str) -> Result<Option<ChatHistory>, ConfigError> {
+        Self::load_session_in(&config::user_data_dir(), name)
+    }
+
+    fn load_session_in(dir: &Path, name: &str) -> Result<Option<ChatHistory>, ConfigError> {
+        if name.contains("/") || name.contains(".") {
+            return Err(ConfigError::Io(std::io::Error::other(
+                "Invalid session name!",
+            )));
+        }
+
+        let data_path = dir.join(name);
+
+        let data = match std::fs::read_to_string(&data_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            val => val,
+        }?;
+
+        Ok(Some(serde_json::from_str(&data)?))
+    }
+
+    fn save(name: &str, chat: &ChatHistory) -> Result<(), ConfigError> {
+        Self::save_in(&config::user_data_dir(), name, chat)
+    }
+
+    fn save_in(dir: &Path, name: &str, chat: &ChatHistory) -> Result<(), ConfigError> {
+        Self::save_redacted_in(dir, name, chat, redact::Redactor::configured())
+    }
+
+    fn save_redacted_in(
+        dir: &Path,
+        name: &str,
+        chat: &ChatHistory,
+        redactor: &redact::Redactor,
+    ) -> Result<(), ConfigError> {
+        if name.contains("/") || name.contains(".") {
+            return Err(ConfigError::Io(std::io::Error::other(
+                "Invalid session name!",
+            )));
+        }
+
+        // Ensure the directory exists!
+        std::fs::create_dir_all(dir)?;
+        let data_path = dir.join(name);
+
+        let chat_serialized = json::to_string(&redactor.history(chat), JsonStyle::Compact)?;
+        config::write_atomic(&data_path, chat_serialized.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Saves to the session `name`, or for an unnamed session to a generated name
+    /// which `name` then holds on to, so later saves land in the same file.
+    /// A named session growing past `max_bytes` is rotated first, see [`PastChats::rotate_in`].
+    fn save_to(
+        name: &mut Option<String>,
+        chat: &mut ChatHistory,
+        max_bytes: Option<usize>,
+    ) -> Result<(), ConfigError> {
+        match name {
+            Some(name) => {
+                let dir = config::user_data_dir();
+                if let Some(max_bytes) = max_bytes {
+                    if let Some(archive) = Self::rotate_in(&dir, name, chat, max_bytes)? {
+                        anstream::eprintln!(
+                            "{DIMMED}The session grew past {max_bytes} bytes, archived it as {} and started over{DIMMED:#}",
+                            archive.display()
+                        );
+                    }
+                }
+                Self::save_in(&dir, name, chat)
+            }
+            None => {
+                let timestamp =
+                    UnnamedSessionStrategy::Timestamp.session_name(&chrono::Local::now());
+                let generated =
+                    Self::save_generated_in(&config::user_data_dir(), &timestamp, chat)?;
+                anstream::eprintln!("{DIMMED}Saved as session {generated}{DIMMED:#}");
+                *name = Some(generated);
+                Ok(())
+            }
+        }
+    }
+
+    /// Once `chat` serializes to more than `max_bytes`, writes it to the next free
+    /// `<name>.<n>.archive` and cuts it back to its pinned messages, a fresh start for the server too.
+    fn rotate_in(
+        dir: &Path,
+        name: &str,
+        chat: &mut ChatHistory,
+        max_bytes: usize,
+    ) -> Result<Option<PathBuf>, ConfigError> {
+        let chat_serialized = json::to_string(chat, JsonStyle::Compact)?;
+        if chat_serialized.len() <= max_bytes {
+            return Ok(None);
+        }
+
+        std::fs::create_dir_all(dir)?;
+        let archive = (1..)
+            .map(|n| dir.join(format!("{name}.{n}.archive")))
+            .find(|archive| !archive.exists())
+            .expect("Ran out of archive numbers");
+        let archived =
+            json::to_string(&redact::Redactor::configured().history(chat), JsonStyle::Compact)?;
+        config::write_atomic(&archive, archived.as_bytes())?;
+
+        chat.chat.messages.truncate(chat.pinned);
+        chat.next_vqid.clear();
+        chat.reply_hashes.clear();
+        Ok(Some(archive))
+    }
+
+    /// Saves under a new `<timestamp>-<suffix>` name, never overwriting an existing session.
+    fn save_generated_in(
+        dir: &Path,
+        timestamp: &str,
+        chat: &ChatHistory,
+    ) -> Result<String, ConfigError> {
+        use std::hash::BuildHasher;
+
+        std::fs::create_dir_all(dir)?;
+        let mut suffix = std::collections::hash_map::RandomState::new().hash_one(()) as u16;
+        loop {
+            let name = format!("{timestamp}-{suffix:04x}");
+            // Claiming the name first, so two runs racing for it can't both get it
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(&name))
+            {
+                Ok(_) => {
+                    Self::save_in(dir, &name, chat)?;
+                    return Ok(name);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    suffix = suffix.wrapping_add(1)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+struct ChatBotEvent {
+    action: String,
+    created: u64,
+    message: Option<String>,
+
+    // Doesn't exist for claude3
+    id: Option<String>,
+    model: Option<String>,
+
+    // Nonexistent for everything but claude3
+    role: Option<String>,
+}
+
+/// A chatbot event in the same shape whichever model sent it.
+#[derive(Debug, PartialEq, Eq)]
+struct NormalizedEvent {
+    /// Empty for events without a message, like the closing one
+    content: String,
+    role: ChatRole,
+    /// Only claude3 leaves it out
+    #[allow(dead_code)]
+    id: Option<String>,
+    model: Option<String>,
+}
+
+/// Irons out how the models' events differ:
+/// only claude3 names a role, everyone else only ever sends the assistant's words.
+fn normalize_event(event: &ChatBotEvent, model: GPTModelIdent) -> NormalizedEvent {
+    let role = match (model, event.role.as_deref()) {
+        (GPTModelIdent::Claude3, Some("user")) => ChatRole::User,
+        _ => ChatRole::Assistant,
+    };
+
+    NormalizedEvent {
+        content: event.message.clone().unwrap_or_default(),
+        role,
+        id: event.id.clone(),
+        model: event.model.clone(),
+    }
+}
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    serde::Deserialize,
+    serde::Serialize, // clap::ValueEnum,
+    JsonSchema,
+)]
+enum GPTModelIdent {
+    #[default]
+    #[serde(rename = "gpt-4o-mini")]
+    GPT4oMini,
+    #[serde(rename = "claude-3-haiku-20240307")]
+    Claude3,
+    #[serde(rename = "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo")]
+    Llama3,
+    #[serde(rename = "mistralai/Mixtral-8x7B-Instruct-v0.1")]
+    Mixtral,
+}
+
+impl GPTModelIdent {
+    /// The identifier the API knows the model by, the same as its serde name.
+    fn as_api_str(&self) -> &'static str {
+        match self {
+            GPTModelIdent::GPT4oMini => "gpt-4o-mini",
+            GPTModelIdent::Claude3 => "claude-3-haiku-20240307",
+            GPTModelIdent::Llama3 => "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo",
+            GPTModelIdent::Mixtral => "mistralai/Mixtral-8x7B-Instruct-v0.1",
+        }
+    }
+
+    /// Roughly how many characters of conversation the model still handles well,
+    /// about four per token of its context window with room left for the reply.
+    fn context_budget_chars(&self) -> usize {
+        match self {
+            GPTModelIdent::GPT4oMini => 400_000,
+            GPTModelIdent::Claude3 => 600_000,
+            GPTModelIdent::Llama3 => 400_000,
+            GPTModelIdent::Mixtral => 100_000,
+        }
+    }
+}
+
+/// A warning for requests larger than `model` handles well, the request counted in characters.
+fn context_warning(model: GPTModelIdent, messages: &[ChatMessage]) -> Option<String> {
+    let chars: usize = messages.iter().map(|m| m.content.chars().count()).sum();
+    let budget = model.context_budget_chars();
+    (chars > budget).then(|| {
+        format!(
+            "Warning: the conversation is {chars} characters long, more than the ~{budget} \
+             {model} handles well. Older messages may get ignored, \
+             --rewind or a new session keeps it shorter."
+        )
+    })
+}
+
+/// How many tokens `chars` characters come to, at the four per token budgets are counted in.
+fn estimated_tokens(chars: usize) -> usize {
+    chars.div_ceil(4)
+}
+
+/// The length of the message excerpts [`preview_summary`] shows.
+const PREVIEW_EXCERPT_CHARS: usize = 60;
+
+/// What `--preview` shows of a request: each message, the messages and characters per role,
+/// and the total with its estimated tokens against what the model handles well.
+fn preview_summary(chat: &ChatRequest) -> String {
+    let mut summary = format!("Preview of the request to {}:\n", chat.model);
+    let mut per_role = [(ChatRole::User, 0, 0), (ChatRole::Assistant, 0, 0)];
+    for (index, message) in chat.messages.iter().enumerate() {
+        let chars = message.content.chars().count();
+        let first_line = message.content.lines().next().unwrap_or_default().trim_end();
+        let excerpt = match first_line.char_indices().nth(PREVIEW_EXCERPT_CHARS) {
+            Some((cut, _)) => format!("{}…", &first_line[..cut]),
+            None if first_line.len() < message.content.trim_end().len() => {
+                format!("{first_line}…")
+            }
+            None => first_line.to_owned(),
+        };
+        summary += &format!("  [{index}] {}, {chars} chars: {excerpt}\n", message.role.as_str());
+
+        if let Some((_, count, role_chars)) =
+            per_role.iter_mut().find(|(role, ..)| *role == message.role)
+        {
+            *count += 1;
+            *role_chars += chars;
+        }
+    }
+
+    for (role, count, chars) in per_role {
+        summary += &format!("{}: {count} message(s), {chars} chars\n", role.as_str());
+    }
+    let total: usize = per_role.iter().map(|(_, _, chars)| chars).sum();
+    let budget = chat.model.context_budget_chars();
+    summary += &format!(
+        "Total: {total} chars, about {} tokens of the ~{} {} handles well",
+        estimated_tokens(total),
+        estimated_tokens(budget),
+        chat.model
+    );
+    summary
+}
+
+impl std::fmt::Display for GPTModelIdent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_api_str())
+    }
+}
+
+/// Headers individual models need on the chat request,
+/// the single place to put per-model tweaks should DDG start requiring any.
+#[derive(Debug, Default)]
+struct ModelRequirements {
+    headers: HashMap<GPTModelIdent, HeaderMap>,
+}
+
+impl ModelRequirements {
+    fn builtin() -> Self {
+        // No model needs anything beyond the common headers (yet)
+        ModelRequirements::default()
+    }
+
+    fn headers_for(&self, model: GPTModelIdent) -> HeaderMap {
+        self.headers.get(&model).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FragmentOutcome {
+    /// Not an SSE `data: ` line, nothing to do
+    Ignored,
+    /// The `[DONE]` marker terminating the stream
+    Done,
+    /// A well-formed chatbot event
+    Event(ChatBotEvent),
+    /// A `data: ` line that failed to parse, it was skipped
+    Malformed,
+}
+
+fn display_message_fragment(
+    message_buffer: &[u8],
+    model: GPTModelIdent,
+    verbose: bool,
+    on_fragment: &mut impl FnMut(&str),
+) -> FragmentOutcome {
+    let message = match message_buffer.strip_prefix(b"data: ") {
+        Some(message) => message,
+        None => return FragmentOutcome::Ignored,
+    };
+
+    if message.starts_with(b"[DONE]") {
+        let display_message = String::from_utf8_lossy(message);
+        anstream::eprintln!("\n{DIMMED}{display_message}{DIMMED:#}");
+        let _ = std::io::stderr().flush();
+        return FragmentOutcome::Done;
+    }
+
+    // A single garbled frame shouldn't throw away everything streamed so far
+    let message_deserialized: ChatBotEvent = match serde_json::from_slice(message) {
+        Ok(event) => event,
+        Err(e) => {
+            if verbose {
+                let message_printable = String::from_utf8_lossy(message);
+                anstream::eprintln!(
+                    "\n{DIMMED}Skipping invalid chatbot SSE: {e}\n    MESSAGE: {message_printable}{DIMMED:#}"
+                );
+                let _ = std::io::stderr().flush();
+            }
+            return FragmentOutcome::Malformed;
+        }
+    };
+
+    let event = normalize_event(&message_deserialized, model);
+    if event.role == ChatRole::Assistant && !event.content.is_empty() {
+        on_fragment(&event.content);
+    }
+
+    FragmentOutcome::Event(message_deserialized)
+}
+
+/// Whether the reply is only shown once complete, for options that rework all of it.
+fn reply_held_back(args: &Cli) -> bool {
+    args.strip_markdown || args.unescape || args.no_sources
+}
+
+/// Applies the options that change the reply itself, as it's saved and not just shown.
+fn rework_reply(args: &Cli, reply: String) -> String {
+    if args.unescape {
+        output::unescape(&reply)
+    } else {
+        reply
+    }
+}
+
+/// A held back reply, as it's shown once complete.
+fn held_back_display(args: &Cli, reply: &str) -> String {
+    let reply = if args.no_sources {
+        output::split_sources(reply).0
+    } else {
+        reply
+    };
+
+    if args.strip_markdown {
+        output::strip_markdown(reply)
+    } else {
+        reply.to_owned()
+    }
+}
+
+/// What `--model-info` prints about `model`.
+fn model_info(model: ModelIdentArg) -> String {
+    let possible_value = model
+        .to_possible_value()
+        .expect("every model has a possible value");
+    let aliases: Vec<_> = possible_value.get_name_and_aliases().skip(1).collect();
+    format!(
+        "name:    {}\naliases: {}\napi:     {}",
+        model.as_str(),
+        aliases.join(", "),
+        model.to_model()
+    )
+}
+
+/// What `--list-models` prints, a line per model with its CLI name, its aliases joined by
+/// commas and its API identifier, separated by tabs.
+fn model_list() -> String {
+    ModelIdentArg::value_variants()
+        .iter()
+        .map(|model| {
+            let possible_value = model
+                .to_possible_value()
+                .expect("every model has a possible value");
+            let aliases: Vec<_> = possible_value.get_name_and_aliases().skip(1).collect();
+            format!(
+                "{}\t{}\t{}\n",
+                model.as_str(),
+                aliases.join(","),
+                model.to_model().as_api_str()
+            )
+        })
+        .collect()
+}
+
+fn version_json() -> serde_json::Value {
+    let models: Vec<_> = ModelIdentArg::value_variants()
+        .iter()
+        .map(ModelIdentArg::as_str)
+        .collect();
+
+    serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "models": models,
+        "formats": ["text", "json"],
+    })
+}
+
+/// Renders a unix timestamp in `tz` for people to read, or as RFC3339 in UTC with `utc`.
+fn format_timestamp<Tz: chrono::TimeZone>(unix: u64, tz: &Tz, utc: bool) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let Some(time) = i64::try_from(unix)
+        .ok()
+        .and_then(|unix| chrono::DateTime::from_timestamp(unix, 0))
+    else {
+        return unix.to_string();
+    };
+
+    if utc {
+        time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    } else {
+        time.with_timezone(tz).format("%Y-%m-%d %H:%M").to_string()
+    }
+}
+
+fn print_session_list(
+    json: bool,
+    utc: bool,
+    grep: Option<&regex::Regex>,
+    titles: title::TitleStrategyKind,
+) {
+    let sessions = PastChats::list(&*titles.strategy(), grep)
+        .expect("Failed to list the saved chats, is the data directory accessible?");
+
+    if json {
+        println!(
+            "{}",
+            json::to_string(&sessions, JsonStyle::pretty())
+                .expect("Failed to json-serialize the sessions")
+        );
+        return;
+    }
+
+    let name_width = sessions.iter().map(|s| s.name.len()).max().unwrap_or(0).max(4);
+    let updated_width = if utc { 20 } else { 16 };
+    println!(
+        "{:name_width$}  {:updated_width$}  {:>4}  {:10}  TITLE",
+        "NAME", "UPDATED", "MSGS", "MODEL"
+    );
+    for session in &sessions {
+        println!(
+            "{:name_width$}  {:updated_width$}  {:>4}  {:10}  {}",
+            session.name,
+            format_timestamp(session.updated, &chrono::Local, utc),
+            session.message_count,
+            session.model,
+            session.title
+        );
+    }
+}
+
+fn export_bundle(path: &Path) {
+    let bundle = bundle::export_from(&config::user_data_dir()).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read the saved sessions: {e}");
+        std::process::exit(1);
+    });
+    let serialized =
+        json::to_string(&bundle, JsonStyle::Compact).expect("Failed to json-serialize the bundle");
+    if let Err(e) = config::write_atomic(path, serialized.as_bytes()) {
+        eprintln!("Error: failed to write {}: {e}", path.display());
+        std::process::exit(1);
+    }
+    eprintln!("Exported {} session(s) to {}", bundle.sessions.len(), path.display());
+}
+
+fn import_bundle(path: &Path, overwrite: bool) {
+    let bundle: bundle::Bundle = std::fs::read_to_string(path)
+        .map_err(ConfigError::from)
+        .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to read the bundle {}: {e}", path.display());
+            std::process::exit(1);
+        });
+    let report = bundle::import_into(&config::user_data_dir(), &bundle, overwrite)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to import the bundle: {e}");
+            std::process::exit(1);
+        });
+
+    eprintln!("Imported {} session(s)", report.imported.len());
+    if !report.skipped.is_empty() {
+        eprintln!(
+            "Skipped {} already present, pass --overwrite to replace them: {}",
+            report.skipped.len(),
+            report.skipped.join(", ")
+        );
+    }
+}
+
+fn replay_event_file(args: &Cli, path: &Path, model: GPTModelIdent) {
+    let dump = std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {e}", path.display());
+        std::process::exit(1);
+    });
+
+    let ndjson_out = std::cell::RefCell::new(std::io::stdout());
+    let options = StreamOptions {
+        model,
+        verbose: args.verbose,
+        ndjson: args.ndjson_events.then_some(&ndjson_out as _),
+        ..StreamOptions::default()
+    };
+    let (reply, stats) = replay_events(&dump, &options, &mut |fragment: &str| {
+        if !args.ndjson_events {
+            print!("{fragment}");
+            let _ = std::io::stdout().flush();
+        }
+    });
+    if !args.ndjson_events && !reply.is_empty() {
+        println!();
+    }
+
+    if stats.valid_events == 0 && stats.malformed_events > 0 {
+        eprintln!("Error: {}", TurnError::NoValidEvents(stats.malformed_events));
+        std::process::exit(1);
+    }
+}
+
+fn json_schema(kind: SchemaKind) -> String {
+    let schema = match kind {
+        SchemaKind::Session => schemars::schema_for!(ChatHistory),
+        SchemaKind::Config => schemars::schema_for!(DDGPTConfigDescription),
+    };
+    json::to_string(&schema, JsonStyle::pretty()).expect("Failed to json-serialize the schema")
+}
+
+/// The client builder options the command line can change, split out so tests can record them.
+trait HttpClientSettings: Sized {
+    fn accept_invalid_certs(self, accept: bool) -> Self;
+    fn connect_timeout(self, timeout: Duration) -> Self;
+    fn read_timeout(self, timeout: Duration) -> Self;
+}
+
+impl HttpClientSettings for reqwest::ClientBuilder {
+    fn accept_invalid_certs(self, accept: bool) -> Self {
+        self.danger_accept_invalid_certs(accept)
+    }
+
+    fn connect_timeout(self, timeout: Duration) -> Self {
+        reqwest::ClientBuilder::connect_timeout(self, timeout)
+    }
+
+    fn read_timeout(self, timeout: Duration) -> Self {
+        reqwest::ClientBuilder::read_timeout(self, timeout)
+    }
+}
+
+/// Connecting and reading are timed separately, so an unreachable network can fail fast
+/// without cutting off a model that's slow to stream.
+#[derive(Debug, Default, Clone, Copy)]
+struct ClientTimeouts {
+    connect: Option<Duration>,
+    read: Option<Duration>,
+}
+
+impl ClientTimeouts {
+    /// The flags' timeouts, or else the config's.
+    fn new(args: &Cli, config: &DDGPTConfigDescription) -> Self {
+        ClientTimeouts {
+            connect: args
+                .connect_timeout
+                .or(config.connect_timeout_secs)
+                .map(Duration::from_secs),
+            read: args
+                .read_timeout
+                .or(config.read_timeout_secs)
+                .map(Duration::from_secs),
+        }
+    }
+}
+
+fn apply_client_settings<B: HttpClientSettings>(
+    mut builder: B,
+    args: &Cli,
+    timeouts: ClientTimeouts,
+) -> B {
+    if let Some(timeout) = timeouts.connect {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = timeouts.read {
+        builder = builder.read_timeout(timeout);
+    }
+    if !args.insecure {
+        return builder;
+    }
+
+    anstream::eprintln!(
+        "{WARNING}WARNING: --insecure disables TLS certificate verification, \
+         anyone on the network path can read and alter this conversation!{WARNING:#}"
+    );
+    builder.accept_invalid_certs(true)
+}
+
+fn build_http_client(args: &Cli, timeouts: ClientTimeouts) -> Client {
+    let builder = Client::builder().user_agent("curl/7.81.0");
+
+    apply_client_settings(builder, args, timeouts)
+        .build()
+        .expect("Failed to construct http_client")
+}
+
+/// Splits a leading `@model` line off `query`, for --model-from-prompt.
+/// A first line that doesn't name a model is left in as part of the prompt.
+fn split_model_selector(query: &str) -> (Option<ModelIdentArg>, &str) {
+    let (first_line, rest) = query.split_once('\n').unwrap_or((query, ""));
+    let selected = first_line
+        .trim()
+        .strip_prefix('@')
+        .and_then(|name| resolve_model(name.trim()).ok());
+    match selected {
+        Some(model) => (Some(model), rest.trim_start_matches(['\r', '\n'])),
+        None => (None, query),
+    }
+}
+
+/// The query to send, after the messages that come before it in the conversation.
+struct AssembledQuery {
+    /// The --prompt-editor-template sections other than the question, one message each
+    preamble: Vec<ChatMessage>,
+    query: String,
+}
+
+fn assemble_query(args: &Cli) -> AssembledQuery {
+    // Checked before stdin gets read, the editor needs it to be the terminal
+    if args.edit && !confirm::should_prompt(args.assume_yes, std::io::stdin().is_terminal()) {
+        eprintln!("Error: --edit needs a terminal on stdin to run the editor in, and doesn't go with --yes");
+        std::process::exit(1);
+    }
+    assemble_query_with(args, |asked_for| {
+        // The chat modes read their prompts from stdin, and `--files-from -` already took it
+        let piped = !std::io::stdin().is_terminal()
+            && !args.interactive_session
+            && !args.tui
+            && args.files_from.as_deref() != Some(Path::new("-"));
+        (asked_for || piped).then(|| {
+            let mut input = String::new();
+            std::io::stdin()
+                .read_to_string(&mut input)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: failed to read stdin: {e}");
+                    std::process::exit(1);
+                });
+            input
+        })
+    })
+}
+
+/// The filled in template's instructions and context as messages ahead of the question.
+/// There is no system role, so they go in as the user's words.
+fn template_preamble(prompt: &editor::TemplatePrompt) -> Vec<ChatMessage> {
+    [&prompt.instructions, &prompt.context]
+        .into_iter()
+        .filter(|section| !section.is_empty())
+        .map(|section| ChatMessage {
+            role: ChatRole::User,
+            content: section.clone(),
+            truncated: false,
+        })
+        .collect()
+}
+
+/// Has the user compose the query in their editor, from the template if asked to,
+/// handing back the template's other sections along with it.
+fn edit_query(args: &Cli, query: &str) -> (Vec<ChatMessage>, String) {
+    let initial = if args.prompt_editor_template {
+        format!("{}{query}", editor::TEMPLATE)
+    } else {
+        query.to_owned()
+    };
+    let edited = editor::edit(&initial).unwrap_or_else(|e| {
+        eprintln!("Error: failed to edit the prompt: {e}");
+        std::process::exit(1);
+    });
+
+    if !args.prompt_editor_template {
+        return (Vec::new(), edited.trim().to_owned());
+    }
+    match editor::parse_template(&edited) {
+        Ok(prompt) => (template_preamble(&prompt), prompt.question),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// [`assemble_query`] with stdin read by `read_stdin`, which is told whether the query
+/// has a placeholder for it and hands back its content if there is any to use.
+fn assemble_query_with(
+    args: &Cli,
+    read_stdin: impl FnOnce(bool) -> Option<String>,
+) -> AssembledQuery {
+    let mut query = match &args.prompt {
+        Some(prompt) => prompt.clone(),
+        None => args.query.join(args.join.separator()),
+    };
+
+    if let Some(stdin) = read_stdin(query.contains(prompt::STDIN_PLACEHOLDER)) {
+        query = prompt::insert_stdin(&query, &stdin);
+    }
+
+    if args.paste {
+        query = clipboard::prepend_pasted(&mut clipboard::SystemClipboard, &query)
+            .unwrap_or_else(|e| {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            });
+    }
+
+    let mut preamble = Vec::new();
+    if args.edit {
+        (preamble, query) = edit_query(args, &query);
+    }
+
+    for path in &args.include_files {
+        let block = prompt::fenced_file_block(path, args.input_encoding).unwrap_or_else(|e| {
+            eprintln!("Error: failed to include {}: {e}", path.display());
+            std::process::exit(1);
+        });
+        query.push_str("\n\n");
+        query.push_str(&block);
+    }
+
+    if let Some(list_source) = &args.files_from {
+        let paths = prompt::read_file_list(list_source).unwrap_or_else(|e| {
+            eprintln!("Error: failed to read file list {}: {e}", list_source.display());
+            std::process::exit(1);
+        });
+
+        for (path, e) in prompt::append_file_blocks(&mut query, paths, args.input_encoding) {
+            anstream::eprintln!("{DIMMED}Warning: skipping {}: {e}{DIMMED:#}", path.display());
+        }
+    }
+
+    if args.dedent {
+        query = prompt::dedent(&query);
+    }
+    AssembledQuery { preamble, query }
+}
+
+/// The `--context-from` message a new conversation starts with, if any.
+fn context_message(args: &Cli) -> Option<ChatMessage> {
+    let path = args.context_from.as_ref()?;
+    let content = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read the context from {}: {e}", path.display());
+        std::process::exit(1);
+    });
+
+    Some(ChatMessage {
+        role: args.context_role,
+        content,
+        truncated: false,
+    })
+}
+
+/// The messages a new conversation starts with: the date if asked for,
+/// then the `--context-from` message.
+fn opening_messages(
+    args: &Cli,
+    inject_datetime: bool,
+    today: chrono::NaiveDate,
+    system_prefix: Option<&str>,
+) -> Vec<ChatMessage> {
+    if args.messages_json.is_some() {
+        return Vec::new();
+    }
+    // There is no system role, the prefix and date go in as the user's own words
+    let prefix_message = system_prefix.map(|prefix| ChatMessage {
+        role: ChatRole::User,
+        content: prefix.to_owned(),
+        truncated: false,
+    });
+    let date_message = (args.with_date || inject_datetime).then(|| ChatMessage {
+        role: ChatRole::User,
+        content: format!("The current date is {}.", today.format("%Y-%m-%d")),
+        truncated: false,
+    });
+    prefix_message
+        .into_iter()
+        .chain(date_message)
+        .chain(context_message(args))
+        .collect()
+}
+
+/// Sets the Authorization header, if there's anything to authorize with.
+fn add_auth(headers: &mut HeaderMap, auth: Option<&HeaderValue>) {
+    if let Some(auth) = auth {
+        headers.insert(header::AUTHORIZATION, auth.clone());
+    }
+}
+
+/// The status request handing out the vqid, a fresh one if `new_session`.
+fn build_status_request(
+    client: &Client,
+    new_session: bool,
+    auth: Option<&HeaderValue>,
+) -> reqwest::Request {
+    let mut headers = HeaderMap::new();
+    // We need to request a new session ID
+    if new_session {
+        headers.insert("x-vqd-accept", HeaderValue::from_static("1"));
+    }
+    add_auth(&mut headers, auth);
+
+    client
+        .get("https://duckduckgo.com/duckchat/v1/status")
+        .headers(headers)
+        .build()
+        .unwrap()
+}
+
+fn build_chat_request(
+    client: &Client,
+    vqid: &str,
+    extra_headers: &HeaderMap,
+    body: String,
+) -> reqwest::Request {
+    client
+        .post(Url::parse("https://duckduckgo.com/duckchat/v1/chat").unwrap())
+        .header(header::ACCEPT, "text/event-stream")
+        .header(header::CONTENT_TYPE, "application/json")
+        // .header(header::COOKIE, value)
+        .header(VQD_HEADER, vqid)
+        // Replaces any of the defaults above with the same name
+        .headers(extra_headers.clone())
+        .body(
+            // r#"{"model":"gpt-4o-mini","messages":[{"content": "foobar", "role": "user"}]}"#
+            body,
+        )
+        .build()
+        .unwrap()
+}
+
+/// A chat reply as it arrives, split out of [`reqwest::Response`] so tests can cut it off.
+trait ReplyStream {
+    /// The ID the next request of this conversation has to carry, if the server handed one out.
+    fn vqid(&self) -> Option<String>;
+    fn content_type(&self) -> Option<String>;
+    async fn next_chunk(&mut self) -> reqwest::Result<Option<Vec<u8>>>;
+}
+
+impl ReplyStream for reqwest::Response {
+    fn vqid(&self) -> Option<String> {
+        vqid_from_response(self)
+    }
+
+    fn content_type(&self) -> Option<String> {
+        let content_type = self.headers().get(header::CONTENT_TYPE)?;
+        Some(String::from_utf8_lossy(content_type.as_bytes()).into_owned())
+    }
+
+    async fn next_chunk(&mut self) -> reqwest::Result<Option<Vec<u8>>> {
+        Ok(self.chunk().await?.map(Vec::from))
+    }
+}
+
+/// What the status endpoint handed back.
+struct Handshake {
+    /// Only handed out when asked for a new session
+    vqid: Option<String>,
+    /// Where any cookies come from
+    headers: HeaderMap,
+}
+
+/// Talks to DDG, the seam tests use to script the handshake and the replies.
+trait Transport {
+    type Reply: ReplyStream;
+    async fn handshake(&self, new_session: bool) -> reqwest::Result<Handshake>;
+    async fn send(
+        &self,
+        vqid: &str,
+        headers: &HeaderMap,
+        body: String,
+    ) -> reqwest::Result<Self::Reply>;
+}
+
+struct DdgTransport<'a> {
+    client: &'a Client,
+    auth: Option<&'a HeaderValue>,
+}
+
+impl Transport for DdgTransport<'_> {
+    type Reply = reqwest::Response;
+
+    async fn handshake(&self, new_session: bool) -> reqwest::Result<Handshake> {
+        let ddg_status_request = build_status_request(self.client, new_session, self.auth);
+
+        // dbg!(&ddg_status_request);
+        let ddg_status_response = self.client.execute(ddg_status_request).await?;
+        // dbg!(&ddg_status_response);
+        Ok(Handshake {
+            vqid: vqid_from_response(&ddg_status_response),
+            headers: ddg_status_response.headers().clone(),
+        })
+    }
+
+    async fn send(
+        &self,
+        vqid: &str,
+        headers: &HeaderMap,
+        body: String,
+    ) -> reqwest::Result<reqwest::Response> {
+        let ddg_chat_request = build_chat_request(self.client, vqid, headers, body);
+
+        // dbg!(&ddg_chat_request);
+        self.client.execute(ddg_chat_request).await
+    }
+}
+
+fn vqid_from_response(response: &reqwest::Response) -> Option<String> {
+    Some(
+        response
+            .headers()
+            .get(VQD_HEADER)?
+            .to_str()
+            .expect("x-vqd-4 ID was not a valid UTF8-String!")
+            .to_string(),
+    )
+}
+
+/// How much of a response that isn't an event stream ends up in the error.
+const UNEXPECTED_BODY_EXCERPT_LEN: usize = 300;
+
+/// Refuses to stream a response that isn't an event stream, like a block page from DDG
+/// or a proxy, reading it instead to show what came back.
+/// A response without a content type gets the benefit of the doubt.
+async fn check_event_stream(response: &mut impl ReplyStream) -> Result<(), TurnError> {
+    let Some(content_type) = response.content_type() else {
+        return Ok(());
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    if mime.eq_ignore_ascii_case("text/event-stream") {
+        return Ok(());
+    }
+
+    let mut body = Vec::new();
+    while body.len() < UNEXPECTED_BODY_EXCERPT_LEN * 4 {
+        match response.next_chunk().await {
+            Ok(Some(chunk)) => body.extend(chunk),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    let body = String::from_utf8_lossy(&body);
+    let excerpt = body
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(UNEXPECTED_BODY_EXCERPT_LEN)
+        .collect();
+    Err(TurnError::NotAnEventStream {
+        content_type,
+        excerpt,
+    })
+}
+
+/// Asks the model to pick up a reply that was cut off after `partial_reply`.
+fn resume_request_body(chat: &ChatRequest, partial_reply: &str) -> String {
+    let mut chat = chat.clone();
+    chat.messages.push(ChatMessage {
+        role: ChatRole::Assistant,
+        content: partial_reply.to_owned(),
+        truncated: false,
+    });
+    chat.messages.push(ChatMessage {
+        role: ChatRole::User,
+        content: RESUME_PROMPT.to_owned(),
+        truncated: false,
+    });
+    serde_json::to_string(&chat).expect("Failed to json-serialize the request")
+}
+
+/// How a reply stream is handled besides collecting the reply.
+#[derive(Default)]
+struct StreamOptions<'a> {
+    /// The model asked for, whose event format to expect
+    model: GPTModelIdent,
+    verbose: bool,
+    resume_on_drop: bool,
+    /// Spaces out the requests resuming a dropped reply along with the turns' own
+    pacer: Option<&'a pacing::RequestPacer>,
+    /// Receives every raw event before it gets parsed, for `--dump-events`
+    event_dump: Option<&'a std::fs::File>,
+    /// Receives every parsed event as a json line, then a closing `{"type":"done"}`,
+    /// for `--ndjson-events`
+    ndjson: Option<&'a std::cell::RefCell<dyn Write + 'a>>,
+    /// The largest single event accepted, [`DEFAULT_MAX_EVENT_BYTES`] if unset
+    max_event_bytes: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct StreamStats {
+    valid_events: usize,
+    malformed_events: usize,
+    /// The model named by the latest event that named one
+    served_model: Option<String>,
+    /// Set to the limit once an event outgrew it, which ends the stream
+    event_too_large: Option<usize>,
+}
+
+/// When the phases of a turn ended, for `--trace-timing`.
+#[derive(Debug)]
+struct TurnTiming {
+    start: Instant,
+    handshake_done: Option<Instant>,
+    first_token: Option<Instant>,
+    stream_done: Option<Instant>,
+}
+
+impl TurnTiming {
+    fn new(start: Instant) -> Self {
+        TurnTiming {
+            start,
+            handshake_done: None,
+            first_token: None,
+            stream_done: None,
+        }
+    }
+
+    fn handshake_done(&mut self, at: Instant) {
+        self.handshake_done = Some(at);
+    }
+
+    /// Only the first fragment counts.
+    fn token(&mut self, at: Instant) {
+        self.first_token.get_or_insert(at);
+    }
+
+    fn stream_done(&mut self, at: Instant) {
+        self.stream_done = Some(at);
+    }
+
+    /// The phases that got measured, each one timed from the end of the one it follows.
+    fn phases(&self) -> Vec<(&'static str, Duration)> {
+        let since = |from: Option<Instant>, to: Option<Instant>| Some(to?.duration_since(from?));
+        [
+            ("status handshake", since(Some(self.start), self.handshake_done)),
+            ("first token", since(self.handshake_done, self.first_token)),
+            ("streaming", since(self.handshake_done, self.stream_done)),
+        ]
+        .into_iter()
+        .filter_map(|(phase, duration)| Some((phase, duration?)))
+        .collect()
+    }
+
+    fn report(&self) -> String {
+        self.phases()
+            .iter()
+            .map(|(phase, duration)| format!("{phase}: {}ms", duration.as_millis()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Streams a chat response, appending the reply to `assistant_message`
+/// and handing each fragment to `on_fragment` as it arrives.
+async fn stream_reply(
+    response: &mut impl ReplyStream,
+    options: &StreamOptions<'_>,
+    assistant_message: &mut String,
+    on_fragment: &mut impl FnMut(&str),
+    stats: &mut StreamStats,
+) -> reqwest::Result<()> {
+    let mut on_fragment = |fragment: &str| {
+        assistant_message.push_str(fragment);
+        on_fragment(fragment);
+    };
+
+    // Events may straddle network chunks, so the parser has to outlive them
+    let mut chunk_parser = ChunkParser::new(b"\n\n")
+        .with_max_buf(options.max_event_bytes.unwrap_or(DEFAULT_MAX_EVENT_BYTES));
+    while let Some(chunk) = response.next_chunk().await? {
+        handle_chunk(&mut chunk_parser, &chunk, options, &mut on_fragment, stats);
+        if stats.event_too_large.is_some() {
+            return Ok(());
+        }
+    }
+    if let Some(last_event) = chunk_parser.finish() {
+        handle_events(vec![last_event], options, &mut on_fragment, stats);
+    }
+    Ok(())
+}
+
+/// Runs the events of a `--dump-events` file through the same pipeline as a live stream.
+fn replay_events(
+    dump: &[u8],
+    options: &StreamOptions<'_>,
+    on_fragment: &mut impl FnMut(&str),
+) -> (String, StreamStats) {
+    let mut reply = String::new();
+    let mut stats = StreamStats::default();
+    let mut on_fragment = |fragment: &str| {
+        reply.push_str(fragment);
+        on_fragment(fragment);
+    };
+    // The dump delimits events like the stream, so it's parsed the same way, as a single chunk
+    let mut chunk_parser = ChunkParser::new(b"\n\n");
+    handle_chunk(&mut chunk_parser, dump, options, &mut on_fragment, &mut stats);
+    if stats.event_too_large.is_none() {
+        if let Some(last_event) = chunk_parser.finish() {
+            handle_events(vec![last_event], options, &mut on_fragment, &mut stats);
+        }
+    }
+    (reply, stats)
+}
+
+/// A chat request as it went out, to send again when resuming.
+struct OutgoingChat<'a> {
+    headers: &'a HeaderMap,
+    chat: &'a ChatRequest,
+}
+
+/// Streams `response`, and with `--resume-on-drop` asks `transport` for the rest of the reply
+/// whenever the connection drops after some of it arrived.
+/// Hands back how the last stream ended along with the vqid its response carried.
+async fn stream_resuming<T: Transport>(
+    transport: &T,
+    mut response: T::Reply,
+    outgoing: &OutgoingChat<'_>,
+    options: &StreamOptions<'_>,
+    assistant_message: &mut String,
+    on_fragment: &mut impl FnMut(&str),
+    stats: &mut StreamStats,
+) -> (reqwest::Result<()>, Option<String>) {
+    let mut resume_attempts = 0;
+    loop {
+        let result = stream_reply(
+            &mut response,
+            options,
+            assistant_message,
+            on_fragment,
+            stats,
+        )
+        .await;
+        let vqid = response.vqid();
+
+        let can_resume = options.resume_on_drop
+            && !assistant_message.is_empty()
+            && resume_attempts < MAX_RESUME_ATTEMPTS;
+        // Without a vqid the server won't accept the follow-up request
+        let (Err(_), true, Some(vqid)) = (&result, can_resume, &vqid) else {
+            return (result, vqid);
+        };
+
+        resume_attempts += 1;
+        anstream::eprintln!("\n{DIMMED}Connection dropped, resuming the reply ...{DIMMED:#}");
+        if let Some(pacer) = options.pacer {
+            pacer.wait().await;
+        }
+        let resume_body = resume_request_body(outgoing.chat, assistant_message);
+        response = match transport.send(vqid, outgoing.headers, resume_body).await {
+            Ok(response) => response,
+            Err(e) => return (Err(e), None),
+        };
+    }
+}
+
+fn handle_chunk(
+    chunk_parser: &mut ChunkParser,
+    chunk: &[u8],
+    options: &StreamOptions<'_>,
+    on_fragment: &mut impl FnMut(&str),
+    stats: &mut StreamStats,
+) {
+    match chunk_parser.update(chunk) {
+        Ok(messages) => handle_events(messages, options, on_fragment, stats),
+        Err(EventTooLarge(limit)) => stats.event_too_large = Some(limit),
+    }
+}
+
+fn handle_events(
+    messages: Vec<Vec<u8>>,
+    options: &StreamOptions<'_>,
+    on_fragment: &mut impl FnMut(&str),
+    stats: &mut StreamStats,
+) {
+    for message in messages {
+        if let Some(mut dump) = options.event_dump {
+            // The dump is a debugging aid, failing to write it mustn't cut the reply off
+            // Delimited as in the stream, an event may well span lines
+            let _ = dump.write_all(&message).and_then(|()| dump.write_all(b"\n\n"));
+        }
+        let outcome =
+            display_message_fragment(&message, options.model, options.verbose, on_fragment);
+        if let Some(ndjson) = options.ndjson {
+            let line = match &outcome {
+                FragmentOutcome::Event(event) => serde_json::to_string(event).ok(),
+                FragmentOutcome::Done => Some(r#"{"type":"done"}"#.to_owned()),
+                FragmentOutcome::Ignored | FragmentOutcome::Malformed => None,
+            };
+            if let Some(line) = line {
+                // Consumers are reading along, so every line goes out right away
+                let mut ndjson = ndjson.borrow_mut();
+                let _ = writeln!(ndjson, "{line}").and_then(|()| ndjson.flush());
+            }
+        }
+        match outcome {
+            FragmentOutcome::Event(event) => {
+                stats.valid_events += 1;
+                if event.model.is_some() {
+                    stats.served_model = event.model;
+                }
+            }
+            FragmentOutcome::Malformed => stats.malformed_events += 1,
+            FragmentOutcome::Ignored | FragmentOutcome::Done => {}
+        }
+    }
+}
+
+/// Everything a turn needs besides the conversation itself.
+struct TurnContext<'a> {
+    args: &'a Cli,
+    client: &'a Client,
+    extra_headers: &'a HeaderMap,
+    confirm_above_bytes: Option<usize>,
+    max_session_bytes: Option<usize>,
+    event_dump: Option<&'a std::fs::File>,
+    use_cookies: bool,
+    merge_consecutive_roles: bool,
+    pacer: pacing::RequestPacer,
+    normalize_session_case: bool,
+    title_strategy: title::TitleStrategyKind,
+    auth: Option<HeaderValue>,
+    detect_repetition: bool,
+    max_event_bytes: Option<usize>,
+}
+
+#[derive(Debug)]
+enum TurnError {
+    Request(reqwest::Error),
+    MissingVqid,
+    Dropped {
+        source: reqwest::Error,
+        partial_reply: String,
+    },
+    NoValidEvents(usize),
+    EventTooLarge(usize),
+    ConsecutiveRoles(ChatRole),
+    NotAnEventStream {
+        content_type: String,
+        excerpt: String,
+    },
+}
+
+impl TurnError {
+    /// What arrived of a reply before the stream broke off, if anything did.
+    fn partial_reply(&self) -> Option<&str> {
+        match self {
+            TurnError::Dropped { partial_reply, .. } if !partial_reply.is_empty() => {
+                Some(partial_reply)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TurnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TurnError::Request(e) => write!(f, "the request failed: {e}"),
+            TurnError::MissingVqid => {
+                write!(f, "the status endpoint handed out no {VQD_HEADER} ID")
+            }
+            TurnError::Dropped { source, .. } => write!(f, "the reply was cut off: {source}"),
+            TurnError::EventTooLarge(limit) => write!(
+                f,
+                "the chatbot sent an event larger than {limit} bytes, max_event_bytes raises the limit"
+            ),
+            TurnError::NoValidEvents(count) => write!(
+                f,
+                "the chatbot sent {count} event(s), none of which were valid json (rerun with --verbose for details)"
+            ),
+            TurnError::ConsecutiveRoles(role) => write!(
+                f,
+                "the conversation has consecutive {} messages, which merge_consecutive_roles = false refuses to send",
+                role.as_str()
+            ),
+            TurnError::NotAnEventStream {
+                content_type,
+                excerpt,
+            } => write!(
+                f,
+                "the chatbot answered with {content_type} instead of an event stream, likely a block or error page (try again later, or without a proxy): {excerpt}"
+            ),
+        }
+    }
+}
+
+/// What `--fail-on-empty` exits with, unlike the 1 of any other failure.
+const EXIT_EMPTY_REPLY: i32 = 3;
+
+/// Under `--fail-on-empty`, the exit code and the report for a reply without content,
+/// a json object with `--json`.
+fn empty_reply_failure(args: &Cli, reply: &str) -> Option<(i32, String)> {
+    if !args.fail_on_empty || !reply.trim().is_empty() {
+        return None;
+    }
+
+    let message = "the model replied with nothing";
+    let report = if args.json {
+        json::to_string(
+            &serde_json::json!({ "error": "empty_reply", "message": message }),
+            JsonStyle::Compact,
+        )
+        .expect("Failed to json-serialize the report")
+    } else {
+        format!("Error: {message}")
+    };
+    Some((EXIT_EMPTY_REPLY, report))
+}
+
+/// What `--strict-refusals` exits with.
+const EXIT_REFUSAL: i32 = 4;
+
+const DEFAULT_REFUSAL_PHRASES: &[&str] = &[
+    "I can't help with that",
+    "I cannot help with that",
+    "I can't assist with that",
+    "I cannot assist with that",
+    "I'm sorry, but I can't",
+    "I'm sorry, but I cannot",
+];
+
+/// Under `--strict-refusals`, the exit code and the report for a reply starting with one of
+/// `phrases`, a json object with `--json`.
+fn refusal_failure(args: &Cli, phrases: &[String], reply: &str) -> Option<(i32, String)> {
+    if !args.strict_refusals {
+        return None;
+    }
+    // Models like their apostrophes curly
+    let comparable = |text: &str| text.to_lowercase().replace('\u{2019}', "'");
+    let reply = comparable(reply.trim_start());
+    let phrase = phrases
+        .iter()
+        .find(|phrase| !phrase.is_empty() && reply.starts_with(&comparable(phrase)))?;
+
+    let message = format!("the model refused, its reply starts with \"{phrase}\"");
+    let report = if args.json {
+        json::to_string(
+            &serde_json::json!({ "error": "refusal", "message": message }),
+            JsonStyle::Compact,
+        )
+        .expect("Failed to json-serialize the report")
+    } else {
+        format!("Error: {message}")
+    };
+    Some((EXIT_REFUSAL, report))
+}
+
+/// Reports a failed turn, telling whether the partial reply of a dropped one was `kept` in the
+/// session, marked as truncated.
+fn report_turn_error(args: &Cli, error: &TurnError, kept: bool) {
+    eprintln!("\nError: {error}");
+    if error.partial_reply().is_some() {
+        if kept {
+            eprintln!("The partial reply above was saved to the session, marked as truncated");
+        } else {
+            eprintln!("The partial reply above was not saved to the session");
+        }
+        if !args.resume_on_drop {
+            eprintln!("Use --resume-on-drop to continue dropped replies automatically");
+        }
+    }
+}
+
+/// Runs the `--confirm` / size threshold gate for a prompt of `prompt_len` bytes,
+/// returning whether to send it.
+fn confirm_send(ctx: &TurnContext<'_>, prompt_len: usize, model: GPTModelIdent) -> bool {
+    let over_threshold = ctx
+        .confirm_above_bytes
+        .is_some_and(|limit| prompt_len > limit);
+    match confirm::confirm_decision(
+        over_threshold,
+        ctx.args.confirm || ctx.args.preview,
+        ctx.args.assume_yes,
+        std::io::stdin().is_terminal(),
+    ) {
+        confirm::ConfirmDecision::Proceed => true,
+        confirm::ConfirmDecision::Ask => {
+            // With --continue the session's model is used, not the one from the CLI/config
+            let question = format!(
+                "Send {prompt_len} bytes to model {}?",
+                ModelIdentArg::from(model).as_str()
+            );
+            let send = confirm::ask_yes_no(&question);
+            if !send {
+                eprintln!("Aborted.");
+            }
+            send
+        }
+        confirm::ConfirmDecision::Abort => {
+            let flag = if ctx.args.preview { "--preview" } else { "--confirm" };
+            eprintln!("Error: {flag} needs a terminal to ask on, pass --yes to send anyway");
+            false
+        }
+    }
+}
+
+/// Sends the conversation, which has to end with the new user message,
+/// and streams the reply through `on_fragment`.
+/// On success the vqid for the next turn is stored and the complete reply returned,
+/// adding it to the history is left to the caller.
+async fn run_turn(
+    ctx: &TurnContext<'_>,
+    chat_history: &mut ChatHistory,
+    on_fragment: &mut impl FnMut(&str),
+) -> Result<String, TurnError> {
+    let transport = DdgTransport {
+        client: ctx.client,
+        auth: ctx.auth.as_ref(),
+    };
+    run_turn_with(ctx, &transport, chat_history, on_fragment).await
+}
+
+/// [`run_turn`] talking to DDG through `transport`.
+async fn run_turn_with(
+    ctx: &TurnContext<'_>,
+    transport: &impl Transport,
+    chat_history: &mut ChatHistory,
+    on_fragment: &mut impl FnMut(&str),
+) -> Result<String, TurnError> {
+    ctx.pacer.wait().await;
+    let mut timing = TurnTiming::new(Instant::now());
+    // A --vqid token is used as is, if it's rejected that's the error to see
+    let handshake = match ctx.args.vqid.as_deref() {
+        Some(vqid) => {
+            if chat_history.next_vqid.is_empty() {
+                chat_history.next_vqid = vqid.to_owned();
+            }
+            None
+        }
+        None => Some(
+            transport
+                .handshake(chat_history.next_vqid.is_empty())
+                .await
+                .map_err(TurnError::Request)?,
+        ),
+    };
+    timing.handshake_done(Instant::now());
+
+    // dbg!(&chat_history);
+    // dbg!(&serde_json::to_string(&chat_history).unwrap());
+
+    if chat_history.next_vqid.is_empty() {
+        chat_history.next_vqid = handshake
+            .as_ref()
+            .and_then(|handshake| handshake.vqid.clone())
+            .ok_or(TurnError::MissingVqid)?;
+    }
+
+    // dbg!(&chat_history);
+    let mut chat_headers = ModelRequirements::builtin().headers_for(chat_history.chat.model);
+    if ctx.use_cookies {
+        let state_dir = config::user_state_dir();
+        let mut cookie_jar = cookies::CookieJar::load_in(&state_dir);
+        if handshake.is_some_and(|handshake| cookie_jar.capture(&handshake.headers)) {
+            if let Err(e) = cookie_jar.save_in(&state_dir) {
+                eprintln!("Warning: failed to store the session cookies: {e}");
+            }
+        }
+        cookie_jar.add_to(&mut chat_headers);
+    }
+    add_auth(&mut chat_headers, ctx.auth.as_ref());
+    // User supplied headers win over the per-model ones
+    chat_headers.extend(ctx.extra_headers.clone());
+
+    let request_chat = ChatRequest {
+        model: chat_history.chat.model,
+        messages: merge_consecutive_roles(&chat_history.chat.messages, ctx.merge_consecutive_roles)
+            .map_err(TurnError::ConsecutiveRoles)?,
+    };
+    if let Some(warning) = context_warning(request_chat.model, &request_chat.messages) {
+        if !ctx.args.quiet {
+            eprintln!("{warning}");
+        }
+    }
+    let request_body =
+        serde_json::to_string(&request_chat).expect("Failed to json-serialize the request");
+    let mut ddg_chat_response = transport
+        .send(&chat_history.next_vqid, &chat_headers, request_body)
+        .await
+        .map_err(TurnError::Request)?;
+    check_event_stream(&mut ddg_chat_response).await?;
+
+    // DDG still returns 200 even on error ...
+    // if ddg_status_response.status() != 200 {
+    //     eprintln!("Error {:?}", ddg_chat_response.text().await.expect("Failed to fetch message from webserver!"));
+    //     return;
+    // }
+
+    let mut assistant_message = String::new();
+    let mut stats = StreamStats::default();
+    let ndjson_out = std::cell::RefCell::new(std::io::stdout());
+    let stream_options = StreamOptions {
+        model: chat_history.chat.model,
+        verbose: ctx.args.verbose,
+        resume_on_drop: ctx.args.resume_on_drop,
+        pacer: Some(&ctx.pacer),
+        event_dump: ctx.event_dump,
+        ndjson: ctx.args.ndjson_events.then_some(&ndjson_out as _),
+        max_event_bytes: ctx.max_event_bytes,
+    };
+    let (stream_result, response_vqid) = stream_resuming(
+        transport,
+        ddg_chat_response,
+        &OutgoingChat {
+            headers: &chat_headers,
+            chat: &request_chat,
+        },
+        &stream_options,
+        &mut assistant_message,
+        &mut |fragment: &str| {
+            timing.token(Instant::now());
+            on_fragment(fragment)
+        },
+        &mut stats,
+    )
+    .await;
+    timing.stream_done(Instant::now());
+    if ctx.args.trace_timing && !ctx.args.quiet {
+        anstream::eprintln!("\n{DIMMED}Timing: {}{DIMMED:#}", timing.report());
+    }
+
+    let assistant_message = rework_reply(ctx.args, assistant_message);
+
+    if let Err(source) = stream_result {
+        return Err(TurnError::Dropped {
+            source,
+            partial_reply: assistant_message,
+        });
+    }
+
+    if let Some(limit) = stats.event_too_large {
+        return Err(TurnError::EventTooLarge(limit));
+    }
+
+    if stats.valid_events == 0 && stats.malformed_events > 0 {
+        return Err(TurnError::NoValidEvents(stats.malformed_events));
+    }
+
+    if !assistant_message.is_empty() {
+        // An empty vqid makes the next turn request a fresh one
+        chat_history.next_vqid = response_vqid.unwrap_or_else(|| {
+            anstream::eprintln!(
+                "{DIMMED}The reply carried no {VQD_HEADER} ID, the next turn will request a new one{DIMMED:#}"
+            );
+            String::new()
+        });
+        chat_history.served_model = stats.served_model;
+    }
+
+    Ok(assistant_message)
+}
+
+/// Asks `model` what `messages` asked the session's model, for `--also-ask`.
+/// The reply is only written to `out`, under a label naming the model, the session stays as it was.
+async fn ask_another_model(
+    ctx: &TurnContext<'_>,
+    transport: &impl Transport,
+    messages: &[ChatMessage],
+    model: ModelIdentArg,
+    out: &mut impl Write,
+) -> Result<String, TurnError> {
+    let mut chat_history = ChatHistory::new(model.to_model(), messages.to_vec());
+
+    let _ = writeln!(out, "\n--- {}", model.as_str());
+    let held_back = reply_held_back(ctx.args);
+    let reply = run_turn_with(ctx, transport, &mut chat_history, &mut |fragment: &str| {
+        if !held_back {
+            let _ = out.write_all(fragment.as_bytes()).and_then(|()| out.flush());
+        }
+    })
+    .await?;
+    if held_back {
+        let _ = out.write_all(held_back_display(ctx.args, &reply).as_bytes());
+    }
+    let _ = writeln!(out);
+    Ok(reply)
+}
+
+/// [`ask_another_model`] for each of `models`, up to `jobs` at a time.
+/// One at a time, each reply streams to `out` as it arrives. Asked at once, the replies are held
+/// back until complete so they don't interleave, and written in the order of `models`.
+async fn ask_other_models(
+    ctx: &TurnContext<'_>,
+    transport: &impl Transport,
+    messages: &[ChatMessage],
+    models: &[ModelIdentArg],
+    jobs: usize,
+    out: &mut impl Write,
+    on_error: &mut impl FnMut(&TurnError),
+) {
+    if jobs <= 1 {
+        for &model in models {
+            if let Err(e) = ask_another_model(ctx, transport, messages, model, out).await {
+                on_error(&e);
+            }
+        }
+        return;
+    }
+
+    let asks = models.iter().map(|&model| async move {
+        let mut reply = Vec::new();
+        let result = ask_another_model(ctx, transport, messages, model, &mut reply).await;
+        (reply, result)
+    });
+    jobs::run_in_order(asks, jobs, |(reply, result)| {
+        let _ = out.write_all(&reply).and_then(|()| out.flush());
+        if let Err(e) = result {
+            on_error(&e);
+        }
+    })
+    .await;
+}
+
+/// Runs a turn for `query`, the chat modes' way of extending the conversation.
+/// The query and its reply only stay in the history once a non-empty reply came back,
+/// or part of one before the stream broke off, which is kept marked as truncated.
+/// After any other failure the history is as it was, ready for a retry.
+async fn send_turn(
+    ctx: &TurnContext<'_>,
+    chat_history: &mut ChatHistory,
+    query: String,
+    on_fragment: &mut impl FnMut(&str),
+) -> Result<String, TurnError> {
+    let checkpoint = chat_history.chat.messages.len();
+    chat_history.chat.messages.push(ChatMessage {
+        role: ChatRole::User,
+        content: query,
+        truncated: false,
+    });
+
+    let result = run_turn(ctx, chat_history, on_fragment).await;
+    match &result {
+        Ok(reply) if !reply.is_empty() => {
+            warn_on_repetition(ctx, chat_history, reply);
+            chat_history.chat.messages.push(ChatMessage {
+                role: ChatRole::Assistant,
+                content: reply.clone(),
+                truncated: false,
+            })
+        }
+        Err(e) if e.partial_reply().is_some() => {
+            chat_history.chat.messages.push(ChatMessage {
+                role: ChatRole::Assistant,
+                content: e.partial_reply().unwrap_or_default().to_owned(),
+                truncated: true,
+            })
+        }
+        _ => chat_history.chat.messages.truncate(checkpoint),
+    }
+    result
+}
+
+/// A warning for a data dir holding more than `max_sessions` sessions.
+fn session_count_warning(dir: &Path, max_sessions: usize) -> Option<String> {
+    let count = PastChats::count_in(dir);
+    (count > max_sessions).then(|| {
+        format!(
+            "Warning: {count} sessions are saved in {}, more than the {max_sessions} expected. \
+             --list-sessions shows the old ones to delete, \
+             max_sessions in the config raises the limit.",
+            dir.display()
+        )
+    })
+}
+
+/// Resolves a model name the way `--model` does.
+fn resolve_model(name: &str) -> Result<ModelIdentArg, clap::Error> {
+    let mut cmd = Cli::command();
+    // Formatting errors needs the arguments' final settings
+    cmd.build();
+    let model_arg = cmd.get_arguments().find(|arg| arg.get_id() == "model");
+    ModelIdentArgParser::configured().parse_ref(&cmd, model_arg, std::ffi::OsStr::new(name))
+}
+
+/// Ends any styling left on and flushes, so output that a panic cuts off isn't lost
+/// and the terminal isn't left dimmed.
+fn panic_cleanup(stdout: &mut impl Write, stderr: &mut impl Write) {
+    let reset = Reset.render().to_string();
+    for out in [stdout as &mut dyn Write, stderr] {
+        let _ = out.write_all(reset.as_bytes()).and_then(|()| out.flush());
+    }
+}
+
+/// Runs [`panic_cleanup`] ahead of the usual panic message.
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // Only a terminal gets the reset, redirected output stays free of escape codes
+        panic_cleanup(&mut anstream::stdout(), &mut anstream::stderr());
+        default_hook(info);
+    }));
+}
+
+#[tokio::main]
+async fn main() {
+    install_panic_hook();
+    // Loaded ahead of the arguments, which need its model matching settings
+    let ddgpt_config = std::env::current_dir().map_or_else(
+        |_| DDGPTConfigDescription::load(),
+        |cwd| DDGPTConfigDescription::load_for_project(&cwd),
+    );
+    if let Ok(config) = &ddgpt_config {
+        let _ = MODEL_MATCHING.set(config.model_matching());
+    }
+
+    let mut args_parsed = Cli::parse_with_env();
+    if let Ok(config) = &ddgpt_config {
+        normalize_session_names(&mut args_parsed, config.normalize_session_case);
+    }
+    let args_parsed = args_parsed;
+    let json_indent = ddgpt_config.as_ref().ok().and_then(|c| c.json_indent.as_deref());
+    if let Some(style) = JsonStyle::requested(args_parsed.json_compact, json_indent) {
+        let _ = json::CONFIGURED.set(style);
+    }
+    if args_parsed.version_json {
+        let version = json::to_string(&version_json(), JsonStyle::Compact)
+            .expect("Failed to json-serialize the version");
+        println!("{version}");
+        return;
+    }
+    if let Some(model) = args_parsed.model_info {
+        println!("{}", model_info(model));
+        return;
+    }
+    if args_parsed.list_models {
+        print!("{}", model_list());
+        return;
+    }
+    if args_parsed.paths {
+        print!("{}", config::paths_report());
+        return;
+    }
+    if args_parsed.restore_config {
+        match DDGPTConfigDescription::restore() {
+            Ok(config::Restored::Backup) => eprintln!("Restored the config from its backup"),
+            Ok(config::Restored::Default) => {
+                eprintln!("No usable config backup, wrote the default config instead")
+            }
+            Err(e) => {
+                eprintln!("Error: failed to restore the config: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(kind) = args_parsed.json_schema {
+        println!("{}", json_schema(kind));
+        return;
+    }
+    if let Some(name) = &args_parsed.show {
+        show_session(name, args_parsed.show_from, args_parsed.show_to);
+        return;
+    }
+    if args_parsed.list_sessions {
+        let titles = ddgpt_config.as_ref().map(|c| c.title_strategy).unwrap_or_default();
+        print_session_list(args_parsed.json, args_parsed.utc, args_parsed.grep.as_ref(), titles);
+        return;
+    }
+    let extra_headers = extra_header_map(&args_parsed);
+    let mut save_name = save_target(&args_parsed)
+        .unwrap_or_else(|e| e.exit())
+        .map(str::to_owned);
+
+    let ddgpt_config = ddgpt_config
+        .expect("Could not load / access / initialize the general configuration file");
+    match redact::Redactor::new(&ddgpt_config.redact_patterns) {
+        Ok(redactor) => {
+            let _ = redact::CONFIGURED.set(redactor);
+        }
+        Err(e) => {
+            eprintln!("Error: invalid pattern in redact_patterns: {e}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &args_parsed.export_bundle {
+        export_bundle(path);
+        return;
+    }
+    if let Some(path) = &args_parsed.import_bundle {
+        import_bundle(path, args_parsed.overwrite);
+        return;
+    }
+    if let Some(path) = &args_parsed.replay_events {
+        let model = args_parsed.model.unwrap_or(ddgpt_config.default_chatbot);
+        replay_event_file(&args_parsed, path, model.to_model());
+        return;
+    }
+    // dbg!(&args_parsed);
+
+    // Scripted messages are the whole conversation, stdin included
+    let AssembledQuery {
+        preamble,
+        mut query,
+    } = match args_parsed.messages_json {
+        Some(_) => AssembledQuery {
+            preamble: Vec::new(),
+            query: String::new(),
+        },
+        None => assemble_query(&args_parsed),
+    };
+    let mut selected_model = None;
+    if args_parsed.model_from_prompt {
+        let (selected, rest) = split_model_selector(&query);
+        selected_model = selected;
+        query = rest.to_owned();
+    }
+
+    let model_arg = selected_model
+        .or(args_parsed.model)
+        .unwrap_or(ddgpt_config.default_chatbot);
+    let model = model_arg.to_model();
+
+    anstream::eprintln!(
+        "{DIMMED}Using model: {} (\"{model}\"){DIMMED:#}\n",
+        model_arg.as_str()
+    );
+    let _ = std::io::stderr().flush();
+
+    // Loaded up front, a missing reply to compare with shouldn't cost a request first
+    let compare_base = args_parsed.compare_with.as_deref().map(|name| {
+        PastChats::load_session_from_name(name)
+            .ok()
+            .flatten()
+            .and_then(|history| {
+                let last_reply = history
+                    .chat
+                    .messages
+                    .into_iter()
+                    .rfind(|message| message.role == ChatRole::Assistant);
+                last_reply.map(|message| message.content)
+            })
+            .unwrap_or_else(|| {
+                eprintln!("Error: session '{name}' has no reply to compare with");
+                std::process::exit(1);
+            })
+    });
+
+    let max_sessions = ddgpt_config.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS);
+    // Not even counting under --quiet
+    let count_warning = (!args_parsed.quiet)
+        .then(|| session_count_warning(&config::user_data_dir(), max_sessions))
+        .flatten();
+    if let Some(warning) = count_warning {
+        eprintln!("{warning}");
+    }
+
+    let continuing = args_parsed.continue_session || args_parsed.continue_if_exists;
+
+    // Unnamed new chats may keep adding to a session shared across runs
+    let shared_session = (save_name.is_none()
+        && !continuing
+        && args_parsed.context_from.is_none()
+        && args_parsed.messages_json.is_none())
+    .then(|| {
+        ddgpt_config
+            .unnamed_session_strategy
+            .shared_session(&chrono::Local::now())
+    })
+    .flatten();
+    let shared_history = shared_session.as_deref().and_then(|name| {
+        PastChats::load_session_from_name(name)
+            .expect("Failed to load the shared session, is the data directory accessible?")
+    });
+    if shared_session.is_some() {
+        save_name = shared_session;
+    }
+
+    let mut chat_history = continuing
+        .then(|| {
+            let session_name = args_parsed.session_name.as_deref();
+            let continued = PastChats::load_continued(session_name, args_parsed.continue_if_exists)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: failed to load the chat to continue: {e}");
+                    std::process::exit(1);
+                });
+
+            match continued {
+                // Unless told otherwise, the continued session is saved back in place
+                Some((name, history)) => {
+                    save_name.get_or_insert(name);
+                    Some(history)
+                }
+                None => {
+                    if let Some(name) = session_name {
+                        anstream::eprintln!(
+                            "{DIMMED}No session named '{name}' yet, starting it{DIMMED:#}"
+                        );
+                    }
+                    None
+                }
+            }
+        })
+        .flatten()
+        .or(shared_history)
+        .unwrap_or_else(|| {
+            let messages = opening_messages(
+                &args_parsed,
+                ddgpt_config.inject_datetime,
+                chrono::Local::now().date_naive(),
+                ddgpt_config.system_prefix(model_arg),
+            );
+            ChatHistory {
+                pinned: messages.len(),
+                ..ChatHistory::new(model, messages)
+            }
+        });
+
+    if let Some(vqid) = &args_parsed.vqid {
+        chat_history.next_vqid = vqid.clone();
+    }
+
+    // What --no-append has to leave untouched, whatever rewinding does below
+    let loaded_messages = chat_history.chat.messages.clone();
+
+    if let Some(turns) = args_parsed.rewind {
+        let dropped = truncate_turns(&mut chat_history.chat.messages, chat_history.pinned, turns);
+        if dropped < turns {
+            anstream::eprintln!(
+                "{DIMMED}The session only had {dropped} turn(s) to rewind{DIMMED:#}"
+            );
+        }
+    }
+
+    if args_parsed.probe || ddgpt_config.preflight_probe {
+        if let Err(e) = preflight::probe(preflight::PROBE_ADDRESS, preflight::PROBE_TIMEOUT).await {
+            eprintln!("Error: no network connectivity, {}: {e}", preflight::PROBE_ADDRESS);
+            std::process::exit(1);
+        }
+    }
+
+    let client = build_http_client(
+        &args_parsed,
+        ClientTimeouts::new(&args_parsed, &ddgpt_config),
+    );
+    let event_dump = args_parsed.dump_events.as_ref().map(|path| {
+        std::fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("Error: failed to create {}: {e}", path.display());
+            std::process::exit(1);
+        })
+    });
+    let auth = args_parsed.auth.clone().or_else(|| {
+        let raw = ddgpt_config.auth_header.as_deref()?;
+        Some(parse_auth(raw).unwrap_or_else(|e| {
+            eprintln!("Error: auth_header in the config is unusable, {e}");
+            std::process::exit(1);
+        }))
+    });
+
+    let turn_context = TurnContext {
+        args: &args_parsed,
+        client: &client,
+        extra_headers: &extra_headers,
+        confirm_above_bytes: ddgpt_config.confirm_above_bytes,
+        max_session_bytes: ddgpt_config.max_session_bytes,
+        event_dump: event_dump.as_ref(),
+        use_cookies: ddgpt_config.use_cookies,
+        merge_consecutive_roles: ddgpt_config.merge_consecutive_roles.unwrap_or(true),
+        pacer: pacing::RequestPacer::new(
+            ddgpt_config
+                .min_request_interval_ms
+                .map_or(pacing::DEFAULT_MIN_INTERVAL, Duration::from_millis),
+        ),
+        normalize_session_case: ddgpt_config.normalize_session_case,
+        title_strategy: ddgpt_config.title_strategy,
+        auth,
+        detect_repetition: ddgpt_config.detect_repetition,
+        max_event_bytes: ddgpt_config.max_event_bytes,
+    };
+
+    let jobs = args_parsed.jobs.or(ddgpt_config.max_concurrent).unwrap_or(1);
+    if let Some(prompt) = &args_parsed.benchmark {
+        let models = match args_parsed.benchmark_models.as_slice() {
+            [] => ModelIdentArg::value_variants(),
+            models => models,
+        };
+        benchmark::run(&turn_context, models, prompt, jobs).await;
+        return;
+    }
+
+    if let Some(name) = &args_parsed.summarize_session {
+        let session = PastChats::load_session_from_name(name)
+            .unwrap_or_else(|e| {
+                eprintln!("Error: failed to load session {name}: {e}");
+                std::process::exit(1);
+            })
+            .unwrap_or_else(|| {
+                eprintln!("Error: there is no session named {name}");
+                std::process::exit(1);
+            });
+
+        // A throwaway conversation, the summarized session stays as it is
+        let mut summary_request = ChatHistory::new(
+            model,
+            vec![ChatMessage {
+                role: ChatRole::User,
+                content: summary_prompt(&session.chat.messages, SUMMARY_MAX_CHARS),
+                truncated: false,
+            }],
+        );
+        let result = run_turn(&turn_context, &mut summary_request, &mut |fragment: &str| {
+            print!("{fragment}");
+            let _ = std::io::stdout().flush();
+        })
+        .await;
+        println!();
+        if let Err(e) = result {
+            report_turn_error(&args_parsed, &e, false);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args_parsed.tui {
+        let first_query = (!query.is_empty()).then_some(query);
+        if let Err(e) = tui::run(&turn_context, chat_history, save_name, first_query).await {
+            eprintln!("Error: the terminal interface failed: {e}");
+            std::process::exit(1);
+        }
+        remember_model(ddgpt_config.remember_last_model, args_parsed.explicit_model());
+        return;
+    }
+
+    if args_parsed.interactive_session {
+        let first_query = (!query.is_empty()).then_some(query);
+        let multiline = args_parsed.multiline.then(|| {
+            ddgpt_config
+                .multiline_sentinel
+                .as_deref()
+                .unwrap_or(DEFAULT_MULTILINE_SENTINEL)
+        });
+        interactive::run(&turn_context, chat_history, save_name, first_query, multiline).await;
+        remember_model(ddgpt_config.remember_last_model, args_parsed.explicit_model());
+        return;
+    }
+
+    let new_messages = match &args_parsed.messages_json {
+        Some(ScriptedMessages(messages)) => messages.clone(),
+        None => preamble
+            .into_iter()
+            .chain([ChatMessage {
+                role: ChatRole::User,
+                content: query,
+                truncated: false,
+            }])
+            .collect(),
+    };
+
+    // Only the new prompt counts, a long session shouldn't make every follow-up "large"
+    let new_bytes = new_messages.iter().map(|message| message.content.len()).sum();
+    if args_parsed.preview {
+        let request = ChatRequest {
+            model: chat_history.chat.model,
+            messages: [&chat_history.chat.messages[..], &new_messages].concat(),
+        };
+        eprintln!("{}", preview_summary(&request));
+    }
+    if !confirm_send(&turn_context, new_bytes, chat_history.chat.model) {
+        std::process::exit(1);
+    }
+    if args_parsed.echo {
+        // stdout is left to the reply alone
+        for message in new_messages.iter().filter(|m| m.role == ChatRole::User) {
+            let _ = output::echo_prompt(&mut std::io::stderr(), &message.content);
+        }
+    }
+    chat_history.chat.messages.extend(new_messages);
+    // What --also-ask asks the other models, before the reply joins it
+    let asked = (!args_parsed.also_ask.is_empty()).then(|| chat_history.chat.messages.clone());
+
+    let mut sinks = output::FanOut::default();
+
+    // Paging only makes sense for someone reading along
+    let pager = (args_parsed.pager && std::io::stdout().is_terminal())
+        .then(|| {
+            let command = output::pager_command(std::env::var("PAGER").ok().as_deref());
+            output::Pager::spawn(&command)
+                .inspect_err(|e| {
+                    eprintln!("Warning: failed to start the pager {}: {e}", command[0]);
+                })
+                .ok()
+        })
+        .flatten();
+    match (pager, args_parsed.output.as_ref()) {
+        (Some(pager), _) => sinks.push(pager),
+        (None, Some(path)) if args_parsed.reply_only_to_file => {
+            let stderr = std::io::stderr();
+            let progress = stderr.is_terminal().then_some(stderr);
+            sinks.push(output::ProgressSink::new(
+                std::io::stdout(),
+                progress,
+                path.display().to_string(),
+            ));
+        }
+        // stdout is taken by the events
+        (None, _) if args_parsed.ndjson_events => {}
+        (None, _) => sinks.push(output::WriterSink::new(
+            "stdout",
+            std::io::stdout(),
+            !args_parsed.no_trailing_newline,
+        )),
+    }
+
+    if let Some(path) = args_parsed.output.as_ref() {
+        let file = std::fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("Error: failed to create {}: {e}", path.display());
+            std::process::exit(1);
+        });
+        sinks.push(output::NormalizedWriter::new(BufWriter::new(file)));
+    }
+
+    if args_parsed.copy {
+        sinks.push(clipboard::ClipboardSink::new(clipboard::SystemClipboard));
+    }
+
+    // Reworking the reply needs all of it, so nothing is shown until it's complete
+    let stream_to_sinks = !reply_held_back(&args_parsed);
+    let mut on_fragment = |fragment: &str| {
+        if stream_to_sinks {
+            sinks.write_fragment(fragment)
+        }
+    };
+    let turn_result = if args_parsed.cache {
+        let ttl = ddgpt_config
+            .prompt_cache_ttl_secs
+            .map_or(cache::DEFAULT_TTL, Duration::from_secs);
+        let prompt_cache = cache::PromptCache::new(config::user_cache_dir().join("prompts"), ttl);
+        let key = cache::PromptCache::key(&chat_history.chat);
+        let fetch = run_turn(&turn_context, &mut chat_history, &mut on_fragment);
+        let (result, cached) = prompt_cache.reply_through(&key, args_parsed.refresh, fetch).await;
+        if let (Ok(reply), true) = (&result, cached) {
+            anstream::eprintln!("{DIMMED}Cached reply{DIMMED:#}");
+            on_fragment(reply);
+        }
+        result
+    } else {
+        run_turn(&turn_context, &mut chat_history, &mut on_fragment).await
+    };
+
+    if !stream_to_sinks {
+        let reply = match &turn_result {
+            Ok(reply) => reply,
+            Err(e) => e.partial_reply().unwrap_or_default(),
+        };
+        sinks.write_fragment(&held_back_display(&args_parsed, reply));
+    }
+    sinks.finish();
+
+    // A reply the stream broke off in the middle of is saved as far as it got, then reported
+    let (assistant_message, dropped) = match turn_result {
+        Ok(reply) => (reply, None),
+        Err(e) => match e.partial_reply() {
+            Some(partial_reply) => (partial_reply.to_owned(), Some(e)),
+            None => {
+                report_turn_error(&args_parsed, &e, false);
+                std::process::exit(1);
+            }
+        },
     };
+    let refusal_phrases = ddgpt_config.refusal_phrases.clone().unwrap_or_else(|| {
+        DEFAULT_REFUSAL_PHRASES
+            .iter()
+            .map(|phrase| phrase.to_string())
+            .collect()
+    });
+    let failure = empty_reply_failure(&args_parsed, &assistant_message)
+        .or_else(|| refusal_failure(&args_parsed, &refusal_phrases, &assistant_message))
+        .filter(|_| dropped.is_none());
+    if let Some((code, report)) = failure {
+        if args_parsed.json {
+            println!("{report}");
+        } else {
+            eprintln!("{report}");
+        }
+        std::process::exit(code);
+    }
+
+    if let (Some(name), Some(base)) = (&args_parsed.compare_with, &compare_base) {
+        println!("\n--- {name}\n+++ this reply");
+        print!("{}", output::line_diff(base, &assistant_message));
+    }
+
+    // A copy saved under --save-as keeps the whole exchange
+    let no_append = args_parsed.no_append && args_parsed.save_as.is_none();
+    if !assistant_message.is_empty() {
+        warn_on_repetition(&turn_context, &mut chat_history, &assistant_message);
+        record_reply(
+            &mut chat_history,
+            loaded_messages,
+            assistant_message,
+            dropped.is_some(),
+            no_append,
+        );
+
+        if !chat_history.chat.messages.is_empty() {
+            title::title_if_missing(&turn_context, &mut chat_history).await;
+            PastChats::save_to(
+                &mut save_name,
+                &mut chat_history,
+                ddgpt_config.max_session_bytes,
+            )
+            .expect("Failed to save the chat!");
+        }
+    }
+
+    if let Some(e) = dropped {
+        report_turn_error(&args_parsed, &e, !no_append);
+        std::process::exit(1);
+    }
+
+    if let Some(asked) = asked {
+        let transport = DdgTransport {
+            client: turn_context.client,
+            auth: turn_context.auth.as_ref(),
+        };
+        ask_other_models(
+            &turn_context,
+            &transport,
+            &asked,
+            &args_parsed.also_ask,
+            jobs,
+            &mut std::io::stdout(),
+            &mut |e: &TurnError| report_turn_error(&args_parsed, e, false),
+        )
+        .await;
+    }
+
+    remember_model(ddgpt_config.remember_last_model, args_parsed.explicit_model());
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    /// Runs raw SSE bytes, split into the given chunks, through the stream pipeline.
+    fn stream_chunks(chunks: &[&[u8]]) -> (String, StreamStats) {
+        let mut chunk_parser = ChunkParser::new(b"\n\n");
+        let mut reply = String::new();
+        let mut stats = StreamStats::default();
+        for chunk in chunks {
+            handle_chunk(
+                &mut chunk_parser,
+                chunk,
+                &StreamOptions::default(),
+                &mut |fragment: &str| reply.push_str(fragment),
+                &mut stats,
+            );
+        }
+        (reply, stats)
+    }
+
+    #[test]
+    fn events_of_every_model_normalize_alike() {
+        let samples = [
+            (
+                GPTModelIdent::GPT4oMini,
+                r#"{"message":"Hi","created":1,"id":"chatcmpl-1","action":"success","model":"gpt-4o-mini-2024-07-18"}"#,
+            ),
+            (
+                GPTModelIdent::Claude3,
+                r#"{"role":"assistant","message":"Hi","created":1,"action":"success","model":"claude-3-haiku-20240307"}"#,
+            ),
+            (
+                GPTModelIdent::Llama3,
+                r#"{"message":"Hi","created":1,"id":"chatcmpl-1","action":"success","model":"meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo"}"#,
+            ),
+            (
+                GPTModelIdent::Mixtral,
+                r#"{"message":"Hi","created":1,"id":"chatcmpl-1","action":"success","model":"mistralai/Mixtral-8x7B-Instruct-v0.1"}"#,
+            ),
+        ];
+
+        for (model, sample) in samples {
+            let event = normalize_event(&serde_json::from_str(sample).unwrap(), model);
+            assert_eq!(event.content, "Hi");
+            assert_eq!(event.role, ChatRole::Assistant);
+            assert_eq!(event.id.is_some(), model != GPTModelIdent::Claude3);
+            assert!(event.model.is_some());
+        }
+
+        // The closing event carries no message
+        let closing = r#"{"created":1,"action":"success","model":"claude-3-haiku-20240307"}"#;
+        let closing = serde_json::from_str(closing).unwrap();
+        let event = normalize_event(&closing, GPTModelIdent::Claude3);
+        assert_eq!(event.content, "");
+        assert_eq!(event.role, ChatRole::Assistant);
+
+        // A role other than the assistant's is only taken from claude3
+        let echoed = r#"{"role":"user","message":"Hi","created":1,"action":"success"}"#;
+        let echoed = serde_json::from_str(echoed).unwrap();
+        assert_eq!(normalize_event(&echoed, GPTModelIdent::Claude3).role, ChatRole::User);
+        assert_eq!(normalize_event(&echoed, GPTModelIdent::Llama3).role, ChatRole::Assistant);
+    }
+
+    #[test]
+    fn malformed_event_is_skipped() {
+        let (reply, stats) = stream_chunks(&[
+            b"data: {\"action\":\"success\",\"created\":1,\"message\":\"Hello\"}\n\n",
+            b"data: {\"action\":\"succ\xff garbage\n\n",
+            b"data: {\"action\":\"success\",\"created\":2,\"message\":\", world\"}\n\n",
+            b"data: [DONE]\n\n",
+        ]);
+
+        assert_eq!(reply, "Hello, world");
+        assert_eq!(stats.valid_events, 2);
+        assert_eq!(stats.malformed_events, 1);
+    }
+
+    #[test]
+    fn served_model_is_captured_from_the_events() {
+        let (reply, stats) = stream_chunks(&[
+            b"data: {\"action\":\"success\",\"created\":1,\"model\":\"gpt-4o-mini-2024-07-18\",",
+            b"\"message\":\"Hi\"}\n\n",
+            // Events without a model don't clear it
+            b"data: {\"action\":\"success\",\"created\":2,\"message\":\"!\"}\n\n",
+        ]);
+
+        assert_eq!(reply, "Hi!");
+        assert_eq!(stats.served_model.as_deref(), Some("gpt-4o-mini-2024-07-18"));
+    }
+
+    #[test]
+    fn timing_phases_follow_each_other() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let mut timing = TurnTiming::new(start);
+        timing.handshake_done(at(120));
+        assert_eq!(timing.report(), "status handshake: 120ms");
+
+        timing.token(at(400));
+        timing.token(at(450));
+        timing.stream_done(at(1620));
+        assert_eq!(
+            timing.report(),
+            "status handshake: 120ms, first token: 280ms, streaming: 1500ms"
+        );
+    }
+
+    #[test]
+    fn event_split_across_chunks_survives() {
+        let (reply, stats) = stream_chunks(&[
+            b"data: {\"action\":\"success\",\"crea",
+            b"ted\":1,\"message\":\"Hello\"}\n",
+            b"\n",
+        ]);
+
+        assert_eq!(reply, "Hello");
+        assert_eq!(stats.malformed_events, 0);
+    }
+
+    #[test]
+    fn forced_ambiguous_model_takes_the_top_match() {
+        let model = |name: &str| {
+            Cli::try_parse_from(["ddgpt", "-m", name, "hi"])
+                .map(|args| args.model)
+                .map_err(|e| e.kind())
+        };
+
+        // Close to both llama3 and claude3
+        assert_eq!(model("clla3"), Err(ErrorKind::InvalidValue));
+        assert_eq!(model("clla3!"), Ok(Some(ModelIdentArg::Llama3)));
+        assert_eq!(model("claude!"), Ok(Some(ModelIdentArg::Claude3)));
+    }
+
+    #[test]
+    fn config_is_restored_from_a_valid_backup_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let backup_path = dir.path().join("config.toml.bak");
+        std::fs::write(&config_path, "default_chatbot = [broken").unwrap();
+
+        let backup = "# mine\ndefault_chatbot = \"Claude3\"\n";
+        std::fs::write(&backup_path, backup).unwrap();
+        assert_eq!(
+            DDGPTConfigDescription::restore_in(dir.path()).unwrap(),
+            config::Restored::Backup
+        );
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), backup);
+
+        std::fs::write(&backup_path, "not = [toml").unwrap();
+        assert_eq!(
+            DDGPTConfigDescription::restore_in(dir.path()).unwrap(),
+            config::Restored::Default
+        );
+        let restored = DDGPTConfigDescription::load_in(dir.path()).unwrap();
+        assert_eq!(restored.default_chatbot, ModelIdentArg::default());
+    }
+
+    #[test]
+    fn looser_model_matching_accepts_more_typos() {
+        let cmd = Cli::command();
+        let parse = |matching, name: &str| {
+            ModelIdentArgParser(matching)
+                .parse_ref(&cmd, None, std::ffi::OsStr::new(name))
+                .map_err(|e| e.kind())
+        };
+
+        let config: DDGPTConfigDescription = toml::from_str("").unwrap();
+        assert_eq!(config.model_matching(), ModelMatching::default());
+        assert_eq!(parse(config.model_matching(), "cld3"), Err(ErrorKind::InvalidValue));
+
+        let config: DDGPTConfigDescription = toml::from_str("model_match_threshold = 0.7").unwrap();
+        assert_eq!(parse(config.model_matching(), "cld3"), Ok(ModelIdentArg::Claude3));
+
+        // Close to both llama3 and claude3, fine once no margin is asked for
+        let config: DDGPTConfigDescription = toml::from_str("model_match_margin = 0.0").unwrap();
+        assert_eq!(parse(config.model_matching(), "clla3"), Ok(ModelIdentArg::Llama3));
+    }
+
+    #[test]
+    fn model_selector_line_picks_the_model() {
+        assert_eq!(
+            split_model_selector("@claude3\nwhat's new?"),
+            (Some(ModelIdentArg::Claude3), "what's new?")
+        );
+        assert_eq!(
+            split_model_selector("@gpt4\r\n\nhi"),
+            (Some(ModelIdentArg::GPT4oMini), "hi")
+        );
+    }
+
+    #[test]
+    fn prompt_without_model_selector_is_left_alone() {
+        assert_eq!(split_model_selector("what's new?"), (None, "what's new?"));
+        assert_eq!(
+            split_model_selector("@someone said hi\nreply to them"),
+            (None, "@someone said hi\nreply to them")
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_turn_leaves_the_history_unchanged() {
+        let args = Cli::try_parse_from(["ddgpt", "-i"]).unwrap();
+        // Nothing listens on port 1, so every request fails right away
+        let client = Client::builder()
+            .proxy(reqwest::Proxy::all("http://127.0.0.1:1").unwrap())
+            .build()
+            .unwrap();
+        let ctx = TurnContext {
+            args: &args,
+            client: &client,
+            extra_headers: &HeaderMap::new(),
+            confirm_above_bytes: None,
+            max_session_bytes: None,
+            event_dump: None,
+            use_cookies: false,
+            merge_consecutive_roles: true,
+            pacer: pacing::RequestPacer::new(Duration::ZERO),
+            normalize_session_case: false,
+            title_strategy: title::TitleStrategyKind::default(),
+            auth: None,
+            detect_repetition: false,
+            max_event_bytes: None,
+        };
+        let mut chat_history = history(vec![
+            message(ChatRole::User, "q1"),
+            message(ChatRole::Assistant, "a1"),
+        ]);
+
+        for _ in 0..2 {
+            let result = send_turn(&ctx, &mut chat_history, "q2".to_owned(), &mut |_| {}).await;
+            assert!(matches!(result, Err(TurnError::Request(_))));
+            assert_eq!(contents(&chat_history), ["q1", "a1"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn turn_runs_through_the_fake_transport() {
+        let args = Cli::try_parse_from(["ddgpt", "hello"]).unwrap();
+        let client = Client::new();
+        let ctx = TurnContext {
+            args: &args,
+            client: &client,
+            extra_headers: &HeaderMap::new(),
+            confirm_above_bytes: None,
+            max_session_bytes: None,
+            event_dump: None,
+            use_cookies: false,
+            merge_consecutive_roles: true,
+            pacer: pacing::RequestPacer::new(Duration::ZERO),
+            normalize_session_case: false,
+            title_strategy: title::TitleStrategyKind::default(),
+            auth: None,
+            detect_repetition: false,
+            max_event_bytes: None,
+        };
+        let transport = FakeTransport {
+            status_vqid: Some("first".to_owned()),
+            ..FakeTransport::default()
+        };
+        transport
+            .replies
+            .borrow_mut()
+            .push_back(ScriptedReply::new(vec![
+                event("Hi "),
+                event("there"),
+                Ok(Some(b"data: [DONE]\n\n".to_vec())),
+            ]));
+        let mut chat_history = history(vec![message(ChatRole::User, "hello")]);
+        chat_history.next_vqid.clear();
+
+        let mut shown = String::new();
+        let reply = run_turn_with(&ctx, &transport, &mut chat_history, &mut |fragment: &str| {
+            shown.push_str(fragment)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(reply, "Hi there");
+        assert_eq!(shown, "Hi there");
+        // The handshake's vqid is sent, the reply's kept for the next turn
+        let requests = transport.requests.borrow();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "first");
+        let sent: ChatRequest = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(sent.messages[0].content, "hello");
+        assert_eq!(chat_history.next_vqid, "next");
+    }
+
+    #[tokio::test]
+    async fn also_asked_reply_is_printed_but_not_saved() {
+        let args = Cli::try_parse_from(["ddgpt", "--also-ask", "claude", "hello"]).unwrap();
+        let client = Client::new();
+        let ctx = TurnContext {
+            args: &args,
+            client: &client,
+            extra_headers: &HeaderMap::new(),
+            confirm_above_bytes: None,
+            max_session_bytes: None,
+            event_dump: None,
+            use_cookies: false,
+            merge_consecutive_roles: true,
+            pacer: pacing::RequestPacer::new(Duration::ZERO),
+            normalize_session_case: false,
+            title_strategy: title::TitleStrategyKind::default(),
+            auth: None,
+            detect_repetition: false,
+            max_event_bytes: None,
+        };
+        let transport = FakeTransport {
+            status_vqid: Some("fresh".to_owned()),
+            ..FakeTransport::default()
+        };
+        transport
+            .replies
+            .borrow_mut()
+            .push_back(ScriptedReply::new(vec![event("Bonjour")]));
+        let chat_history = history(vec![
+            message(ChatRole::User, "hello"),
+            message(ChatRole::Assistant, "Hi"),
+        ]);
+
+        let mut out = Vec::new();
+        let model = args.also_ask[0];
+        let asked = &chat_history.chat.messages[..1];
+        let reply = ask_another_model(&ctx, &transport, asked, model, &mut out).await;
+
+        assert_eq!(reply.unwrap(), "Bonjour");
+        assert_eq!(String::from_utf8(out).unwrap(), "\n--- claude3\nBonjour\n");
+        let sent: ChatRequest = serde_json::from_str(&transport.requests.borrow()[0].1).unwrap();
+        assert_eq!(sent.model, GPTModelIdent::Claude3);
+        assert_eq!(sent.messages.len(), 1);
+        // Nothing of the other model's reply makes it into the session
+        assert_eq!(contents(&chat_history), ["hello", "Hi"]);
+    }
+
+    #[tokio::test]
+    async fn dropped_reply_is_recorded_as_truncated() {
+        let args = Cli::try_parse_from(["ddgpt", "hello"]).unwrap();
+        let client = Client::new();
+        let ctx = TurnContext {
+            args: &args,
+            client: &client,
+            extra_headers: &HeaderMap::new(),
+            confirm_above_bytes: None,
+            max_session_bytes: None,
+            event_dump: None,
+            use_cookies: false,
+            merge_consecutive_roles: true,
+            pacer: pacing::RequestPacer::new(Duration::ZERO),
+            normalize_session_case: false,
+            title_strategy: title::TitleStrategyKind::default(),
+            auth: None,
+            detect_repetition: false,
+            max_event_bytes: None,
+        };
+        let transport = FakeTransport::default();
+        transport
+            .replies
+            .borrow_mut()
+            .push_back(ScriptedReply::new(vec![event("Hello t"), Err(connection_error())]));
+        let mut chat_history = history(vec![message(ChatRole::User, "hello")]);
+        let loaded_messages = chat_history.chat.messages.clone();
+
+        let result = run_turn_with(&ctx, &transport, &mut chat_history, &mut |_: &str| {}).await;
+        let error = result.unwrap_err();
+        assert_eq!(error.partial_reply(), Some("Hello t"));
+        let reply = error.partial_reply().unwrap().to_owned();
+        record_reply(&mut chat_history, loaded_messages, reply, true, false);
+
+        assert!(chat_history.chat.messages[1].truncated);
+        assert_eq!(
+            numbered_transcript(&chat_history.chat.messages, 0),
+            "[0] user: hello\n\n[1] assistant: Hello t [truncated]"
+        );
+        let saved = serde_json::to_string(&chat_history).unwrap();
+        assert!(saved.contains(r#""content":"Hello t","truncated":true"#));
+        // The flag stays out of what's sent
+        let merged = merge_consecutive_roles(&chat_history.chat.messages, true).unwrap();
+        assert!(!serde_json::to_string(&merged).unwrap().contains("truncated"));
+    }
+
+    #[tokio::test]
+    async fn models_asked_at_once_print_in_the_order_given() {
+        let args = Cli::try_parse_from([
+            "ddgpt", "--jobs", "2", "--also-ask", "claude", "--also-ask", "llama", "hello",
+        ])
+        .unwrap();
+        let client = Client::new();
+        let ctx = TurnContext {
+            args: &args,
+            client: &client,
+            extra_headers: &HeaderMap::new(),
+            confirm_above_bytes: None,
+            max_session_bytes: None,
+            event_dump: None,
+            use_cookies: false,
+            merge_consecutive_roles: true,
+            pacer: pacing::RequestPacer::new(Duration::ZERO),
+            normalize_session_case: false,
+            title_strategy: title::TitleStrategyKind::default(),
+            auth: None,
+            detect_repetition: false,
+            max_event_bytes: None,
+        };
+        let transport = FakeTransport {
+            status_vqid: Some("fresh".to_owned()),
+            ..FakeTransport::default()
+        };
+        for reply in ["one", "two"] {
+            transport
+                .replies
+                .borrow_mut()
+                .push_back(ScriptedReply::new(vec![event(reply)]));
+        }
+        let asked = [message(ChatRole::User, "hello")];
+
+        let mut out = Vec::new();
+        let mut errors = 0;
+        ask_other_models(&ctx, &transport, &asked, &args.also_ask, 2, &mut out, &mut |_| {
+            errors += 1
+        })
+        .await;
+
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(errors, 0);
+        assert_eq!(transport.requests.borrow().len(), 2);
+        let claude = out.find("\n--- claude3\n").unwrap();
+        let llama = out.find("\n--- llama3\n").unwrap();
+        assert!(claude < llama, "{out}");
+        // Each reply stays in one piece under its label
+        assert!(out.contains("3\none\n") && out.contains("3\ntwo\n"), "{out}");
+    }
+
+    #[tokio::test]
+    async fn given_vqid_skips_the_status_request() {
+        assert!(Cli::try_parse_from(["ddgpt", "--vqid", "", "hello"]).is_err());
+        let args = Cli::try_parse_from(["ddgpt", "--vqid", "captured", "hello"]).unwrap();
+        let client = Client::new();
+        let ctx = TurnContext {
+            args: &args,
+            client: &client,
+            extra_headers: &HeaderMap::new(),
+            confirm_above_bytes: None,
+            max_session_bytes: None,
+            event_dump: None,
+            use_cookies: false,
+            merge_consecutive_roles: true,
+            pacer: pacing::RequestPacer::new(Duration::ZERO),
+            normalize_session_case: false,
+            title_strategy: title::TitleStrategyKind::default(),
+            auth: None,
+            detect_repetition: false,
+            max_event_bytes: None,
+        };
+        let transport = FakeTransport {
+            status_vqid: Some("fresh".to_owned()),
+            ..FakeTransport::default()
+        };
+        transport
+            .replies
+            .borrow_mut()
+            .push_back(ScriptedReply::new(vec![event("Hi")]));
+        let mut chat_history = history(vec![message(ChatRole::User, "hello")]);
+        chat_history.next_vqid.clear();
+
+        let reply = run_turn_with(&ctx, &transport, &mut chat_history, &mut |_: &str| {}).await;
+
+        assert_eq!(reply.unwrap(), "Hi");
+        assert_eq!(transport.handshakes.get(), 0);
+        assert_eq!(transport.requests.borrow()[0].0, "captured");
+    }
+
+    #[test]
+    fn json_compact_puts_the_schema_on_one_line() {
+        // Nothing configured, the schema keeps its indented layout
+        let args = Cli::try_parse_from(["ddgpt", "--json-schema", "session"]).unwrap();
+        assert_eq!(JsonStyle::requested(args.json_compact, None), None);
+        let indented = json_schema(SchemaKind::Session);
+        assert!(indented.contains("\n  \""));
+
+        let args =
+            Cli::try_parse_from(["ddgpt", "--json-compact", "--json-schema", "session"]).unwrap();
+        let style = JsonStyle::requested(args.json_compact, Some("\t")).unwrap();
+        let compact = style.to_string(&schemars::schema_for!(ChatHistory)).unwrap();
+        assert!(!compact.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&indented).unwrap()
+        );
+    }
+
+    #[test]
+    fn no_sources_trims_the_shown_reply() {
+        let reply = "It's 42.\n\nSources:\n- the guide";
+        let args = Cli::try_parse_from(["ddgpt", "hi"]).unwrap();
+        assert!(!reply_held_back(&args));
+
+        let args = Cli::try_parse_from(["ddgpt", "--no-sources", "hi"]).unwrap();
+        assert!(reply_held_back(&args));
+        assert_eq!(held_back_display(&args, reply), "It's 42.");
+    }
+
+    #[test]
+    fn messages_json_becomes_the_request_verbatim() {
+        let json = concat!(
+            r#"[{"role":"assistant","content":"I answer in one word."},"#,
+            r#"{"role":"user","content":"Capital of France?"}]"#
+        );
+        let args = Cli::try_parse_from(["ddgpt", "--messages-json", json]).unwrap();
+        let request = ChatRequest {
+            model: GPTModelIdent::GPT4oMini,
+            messages: args.messages_json.unwrap().0,
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            format!(r#"{{"model":"gpt-4o-mini","messages":{json}}}"#)
+        );
+    }
+
+    #[test]
+    fn messages_json_is_validated() {
+        let parse = |json: &str| {
+            Cli::try_parse_from(["ddgpt", "--messages-json", json]).map_err(|e| e.kind())
+        };
+        assert_eq!(parse("[]").unwrap_err(), ErrorKind::ValueValidation);
+        assert_eq!(
+            parse(r#"[{"role":"system","content":"be terse"}]"#).unwrap_err(),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            parse(r#"[{"role":"user","content":"hi"},{"role":"assistant","content":"hey"}]"#)
+                .unwrap_err(),
+            ErrorKind::ValueValidation
+        );
+        let single = r#"[{"role":"user","content":"hi"}]"#;
+        assert!(parse(single).is_ok());
+        assert_eq!(
+            Cli::try_parse_from(["ddgpt", "--messages-json", single, "x"])
+                .map_err(|e| e.kind())
+                .unwrap_err(),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn api_names_match_the_serde_names() {
+        for model in ModelIdentArg::value_variants() {
+            let model = model.to_model();
+            assert_eq!(
+                serde_json::to_value(model).unwrap(),
+                model.as_api_str(),
+                "{model:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn model_info_resolves_aliases() {
+        let args = Cli::try_parse_from(["ddgpt", "--model-info", "gpt4"]).unwrap();
+        let info = model_info(args.model_info.unwrap());
+        assert!(info.contains("gpt4o-mini"));
+        assert!(info.contains("gpt4o, gpt4"));
+        assert!(info.lines().last().unwrap().ends_with("gpt-4o-mini"));
+    }
+
+    #[test]
+    fn model_list_has_a_line_per_model() {
+        let list = model_list();
+        let lines: Vec<_> = list.lines().collect();
+        assert_eq!(lines.len(), ModelIdentArg::value_variants().len());
+        assert_eq!(lines[0], "gpt4o-mini\tgpt4o,gpt4\tgpt-4o-mini");
+        assert!(lines.contains(&"claude3\tclaude\tclaude-3-haiku-20240307"));
+
+        assert!(Cli::try_parse_from(["ddgpt", "--list-models"]).is_ok());
+    }
+
+    #[test]
+    fn version_json_lists_every_model() {
+        let version: serde_json::Value = serde_json::from_str(&version_json().to_string()).unwrap();
+
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        let models = version["models"].as_array().unwrap();
+        for model in ModelIdentArg::value_variants() {
+            assert!(models.contains(&model.as_str().into()), "{model:?} missing");
+        }
+    }
+
+    #[test]
+    fn extra_header_parsing() {
+        let parsed = parse_extra_header("X-Debug:  yes ").unwrap();
+        assert_eq!(parsed.name, "x-debug");
+        assert_eq!(parsed.value, "yes");
+
+        assert!(parse_extra_header("no separator").is_err());
+        assert!(parse_extra_header("bad name: value").is_err());
+    }
+
+    #[test]
+    fn extra_headers_end_up_on_the_chat_request() {
+        let mut extra_headers = HeaderMap::new();
+        for raw in ["X-Debug: 1", "Accept: application/json"] {
+            let ExtraHeader { name, value } = parse_extra_header(raw).unwrap();
+            extra_headers.insert(name, value);
+        }
+
+        let request = build_chat_request(&Client::new(), "vqid", &extra_headers, String::new());
+        assert_eq!(request.headers()["x-debug"], "1");
+        assert_eq!(request.headers()[VQD_HEADER], "vqid");
+        // Overridden, not appended
+        let accept: Vec<_> = request.headers().get_all(header::ACCEPT).iter().collect();
+        assert_eq!(accept, ["application/json"]);
+    }
+
+    #[test]
+    fn auth_header_is_on_both_requests_when_configured() {
+        let auth = parse_auth("Bearer secret").unwrap();
+        let status = build_status_request(&Client::new(), true, Some(&auth));
+        assert_eq!(status.headers()[header::AUTHORIZATION], "Bearer secret");
+        assert!(status.headers()[header::AUTHORIZATION].is_sensitive());
+
+        let mut chat_headers = HeaderMap::new();
+        add_auth(&mut chat_headers, Some(&auth));
+        let chat = build_chat_request(&Client::new(), "vqid", &chat_headers, String::new());
+        assert_eq!(chat.headers()[header::AUTHORIZATION], "Bearer secret");
+
+        let status = build_status_request(&Client::new(), false, None);
+        assert!(!status.headers().contains_key(header::AUTHORIZATION));
+        assert!(!status.headers().contains_key("x-vqd-accept"));
+    }
+
+    #[test]
+    fn empty_reply_fails_under_fail_on_empty() {
+        let (reply, _) = stream_chunks(&[
+            b"data: {\"action\":\"success\",\"created\":1,\"model\":\"gpt-4o-mini\"}\n\n",
+            b"data: [DONE]\n\n",
+        ]);
+        assert_eq!(reply, "");
+
+        let args = Cli::try_parse_from(["ddgpt", "hi"]).unwrap();
+        assert_eq!(empty_reply_failure(&args, &reply), None);
+
+        let args = Cli::try_parse_from(["ddgpt", "--fail-on-empty", "hi"]).unwrap();
+        let (code, report) = empty_reply_failure(&args, &reply).unwrap();
+        assert_eq!(code, EXIT_EMPTY_REPLY);
+        assert_eq!(report, "Error: the model replied with nothing");
+        assert_eq!(empty_reply_failure(&args, "Hi"), None);
+
+        let args = Cli::try_parse_from(["ddgpt", "--fail-on-empty", "--json", "hi"]).unwrap();
+        let (code, report) = empty_reply_failure(&args, " \n").unwrap();
+        assert_eq!(code, EXIT_EMPTY_REPLY);
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["error"], "empty_reply");
+    }
+
+    #[test]
+    fn reply_starting_with_a_refusal_phrase_fails_under_strict_refusals() {
+        let phrases = ["I can't help with that".to_owned()];
+        let refusal = "  i can't help with that request.";
+
+        let args = Cli::try_parse_from(["ddgpt", "hi"]).unwrap();
+        assert_eq!(refusal_failure(&args, &phrases, refusal), None);
+
+        let args = Cli::try_parse_from(["ddgpt", "--strict-refusals", "hi"]).unwrap();
+        let (code, report) = refusal_failure(&args, &phrases, refusal).unwrap();
+        assert_eq!(code, EXIT_REFUSAL);
+        assert!(report.starts_with("Error: the model refused"));
+        assert!(refusal_failure(&args, &phrases, "I can\u{2019}t help with that.").is_some());
+        assert_eq!(refusal_failure(&args, &phrases, "Sure, here it is."), None);
+        // Only the start of the reply counts
+        assert_eq!(refusal_failure(&args, &phrases, "Yes. I can't help with that"), None);
+    }
+
+    #[test]
+    fn leading_bom_is_stripped() {
+        let (reply, stats) = stream_chunks(&[
+            b"\xEF\xBB\xBFdata: {\"action\":\"success\",\"created\":1,\"message\":\"Hi\"}\n\n",
+        ]);
+        assert_eq!(reply, "Hi");
+        assert_eq!(stats.valid_events, 1);
+    }
+
+    #[test]
+    fn split_bom_and_blank_lines_are_stripped() {
+        let mut chunk_parser = ChunkParser::new(b"\n\n");
+        assert!(chunk_parser.update(b"\xEF").unwrap().is_empty());
+        assert!(chunk_parser.update(b"\xBB\xBF\r\n\n").unwrap().is_empty());
+        assert_eq!(chunk_parser.update(b"data: x\n\n").unwrap(), vec![b"data: x".to_vec()]);
+    }
+
+    #[test]
+    fn event_fed_byte_by_byte_comes_out_whole() {
+        let event = br#"data: {"action":"success","created":1,"message":"Hi"}"#;
+        let mut chunk_parser = ChunkParser::new(b"\n\n");
+        let mut events = Vec::new();
+        for byte in event.iter().chain(b"\n\n") {
+            events.extend(chunk_parser.update(&[*byte]).unwrap());
+        }
+        assert_eq!(events, vec![event.to_vec()]);
+        assert_eq!(chunk_parser.finish(), None);
+    }
+
+    #[tokio::test]
+    async fn event_without_a_final_delimiter_is_flushed() {
+        let mut response = ScriptedReply::new(vec![
+            event("Hi"),
+            Ok(Some(br#"data: {"action":"success","created":1,"message":" there"}"#.to_vec())),
+            Ok(Some(b"\n".to_vec())),
+        ]);
+        let mut reply = String::new();
+        let mut stats = StreamStats::default();
+        let options = StreamOptions::default();
+        stream_reply(&mut response, &options, &mut reply, &mut |_| {}, &mut stats)
+            .await
+            .unwrap();
+        assert_eq!(reply, "Hi there");
+        assert_eq!(stats.valid_events, 2);
+    }
+
+    #[test]
+    fn event_outgrowing_the_limit_is_an_error() {
+        let mut chunk_parser = ChunkParser::new(b"\n\n").with_max_buf(16);
+        assert!(chunk_parser.update(b"data: 0123456789").unwrap().is_empty());
+        assert_eq!(chunk_parser.update(b"ab"), Err(EventTooLarge(16)));
+
+        // Only what's left unfinished counts
+        let mut chunk_parser = ChunkParser::new(b"\n\n").with_max_buf(16);
+        let events = chunk_parser.update(b"data: 0123456789abcdef\n\ndata: x").unwrap();
+        assert_eq!(events, vec![b"data: 0123456789abcdef".to_vec()]);
+
+        let mut chunk_parser = ChunkParser::new(b"\n\n").with_max_buf(16);
+        let mut stats = StreamStats::default();
+        let options = StreamOptions::default();
+        handle_chunk(&mut chunk_parser, &[b'x'; 17], &options, &mut |_: &str| {}, &mut stats);
+        assert_eq!(stats.event_too_large, Some(16));
+    }
+
+    #[test]
+    fn session_schema_describes_messages() {
+        let schema: serde_json::Value =
+            serde_json::from_str(&json_schema(SchemaKind::Session)).unwrap();
+        assert!(schema["$defs"]["ChatRequest"]["properties"]
+            .get("messages")
+            .is_some());
+
+        let schema: serde_json::Value =
+            serde_json::from_str(&json_schema(SchemaKind::Config)).unwrap();
+        assert!(schema["properties"].get("default_chatbot").is_some());
+    }
+
+    fn message(role: ChatRole, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_owned(),
+            truncated: false,
+        }
+    }
+
+    fn history(messages: Vec<ChatMessage>) -> ChatHistory {
+        ChatHistory {
+            next_vqid: "old".to_owned(),
+            ..ChatHistory::new(GPTModelIdent::GPT4oMini, messages)
+        }
+    }
+
+    fn contents(history: &ChatHistory) -> Vec<&str> {
+        history
+            .chat
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn no_append_leaves_the_continued_session_unchanged() {
+        let mut chat_history = history(vec![
+            message(ChatRole::User, "q1"),
+            message(ChatRole::Assistant, "a1"),
+            message(ChatRole::User, "q2"),
+            message(ChatRole::Assistant, "a2"),
+        ]);
+        let loaded_messages = chat_history.chat.messages.clone();
+
+        // As with --rewind 1 --no-append
+        truncate_turns(&mut chat_history.chat.messages, 0, 1);
+        chat_history.chat.messages.push(message(ChatRole::User, "aside"));
+        chat_history.next_vqid = "new".to_owned();
+        record_reply(&mut chat_history, loaded_messages, "reply".to_owned(), false, true);
+
+        assert_eq!(contents(&chat_history), ["q1", "a1", "q2", "a2"]);
+        assert_eq!(chat_history.next_vqid, "new");
+    }
+
+    #[test]
+    fn reply_is_appended_by_default() {
+        let mut chat_history = history(vec![message(ChatRole::User, "q1")]);
+        record_reply(&mut chat_history, vec![], "a1".to_owned(), false, false);
+        assert_eq!(contents(&chat_history), ["q1", "a1"]);
+    }
+
+    #[test]
+    fn identical_replies_are_detected() {
+        let mut chat_history = history(vec![message(ChatRole::User, "q1")]);
+
+        assert!(!chat_history.note_reply("I can't do that."));
+        assert!(!chat_history.note_reply("Something else."));
+        assert!(chat_history.note_reply("I can't do that.\n"));
+
+        // Only the latest replies are remembered
+        for n in 0..REPETITION_WINDOW {
+            chat_history.note_reply(&n.to_string());
+        }
+        assert_eq!(chat_history.reply_hashes.len(), REPETITION_WINDOW);
+        assert!(!chat_history.note_reply("I can't do that."));
+    }
+
+    #[derive(Default)]
+    struct RecordedSettings {
+        accept_invalid_certs: bool,
+        connect_timeout: Option<Duration>,
+        read_timeout: Option<Duration>,
+    }
+
+    impl HttpClientSettings for RecordedSettings {
+        fn accept_invalid_certs(self, accept: bool) -> Self {
+            RecordedSettings {
+                accept_invalid_certs: accept,
+                ..self
+            }
+        }
+
+        fn connect_timeout(self, timeout: Duration) -> Self {
+            RecordedSettings {
+                connect_timeout: Some(timeout),
+                ..self
+            }
+        }
+
+        fn read_timeout(self, timeout: Duration) -> Self {
+            RecordedSettings {
+                read_timeout: Some(timeout),
+                ..self
+            }
+        }
+    }
+
+    #[test]
+    fn insecure_flag_disables_certificate_checks() {
+        let timeouts = ClientTimeouts::default();
+        let args = Cli::try_parse_from(["ddgpt", "hello"]).unwrap();
+        let settings = apply_client_settings(RecordedSettings::default(), &args, timeouts);
+        assert!(!settings.accept_invalid_certs);
+
+        let args = Cli::try_parse_from(["ddgpt", "--insecure", "hello"]).unwrap();
+        let settings = apply_client_settings(RecordedSettings::default(), &args, timeouts);
+        assert!(settings.accept_invalid_certs);
+    }
+
+    #[test]
+    fn connect_and_read_timeouts_reach_the_builder() {
+        let config: DDGPTConfigDescription =
+            toml::from_str("connect_timeout_secs = 3\nread_timeout_secs = 60").unwrap();
+
+        let args = Cli::try_parse_from(["ddgpt", "hello"]).unwrap();
+        let timeouts = ClientTimeouts::new(&args, &config);
+        let settings = apply_client_settings(RecordedSettings::default(), &args, timeouts);
+        assert_eq!(settings.connect_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(settings.read_timeout, Some(Duration::from_secs(60)));
+
+        // The flags win over the config
+        let args = Cli::try_parse_from(["ddgpt", "--connect-timeout", "1", "hello"]).unwrap();
+        let timeouts = ClientTimeouts::new(&args, &config);
+        let settings = apply_client_settings(RecordedSettings::default(), &args, timeouts);
+        assert_eq!(settings.connect_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(settings.read_timeout, Some(Duration::from_secs(60)));
+
+        // Nothing set, nothing changed
+        let args = Cli::try_parse_from(["ddgpt", "hello"]).unwrap();
+        let timeouts = ClientTimeouts::new(&args, &DDGPTConfigDescription::default());
+        let settings = apply_client_settings(RecordedSettings::default(), &args, timeouts);
+        assert_eq!(settings.connect_timeout, None);
+        assert_eq!(settings.read_timeout, None);
+    }
+
+    /// Stands in for a dropped connection, reqwest has no public way to build its errors.
+    fn connection_error() -> reqwest::Error {
+        Client::new().get("not a url").build().unwrap_err()
+    }
+
+    fn event(message: &str) -> reqwest::Result<Option<Vec<u8>>> {
+        let event =
+            format!("data: {{\"action\":\"success\",\"created\":1,\"message\":\"{message}\"}}\n\n");
+        Ok(Some(event.into_bytes()))
+    }
+
+    struct ScriptedReply {
+        vqid: Option<String>,
+        content_type: Option<String>,
+        chunks: std::collections::VecDeque<reqwest::Result<Option<Vec<u8>>>>,
+    }
+
+    impl ScriptedReply {
+        fn new(chunks: Vec<reqwest::Result<Option<Vec<u8>>>>) -> Self {
+            ScriptedReply {
+                vqid: Some("next".to_owned()),
+                content_type: None,
+                chunks: chunks.into(),
+            }
+        }
+    }
+
+    impl ReplyStream for ScriptedReply {
+        fn vqid(&self) -> Option<String> {
+            self.vqid.clone()
+        }
+
+        fn content_type(&self) -> Option<String> {
+            self.content_type.clone()
+        }
+
+        async fn next_chunk(&mut self) -> reqwest::Result<Option<Vec<u8>>> {
+            self.chunks.pop_front().unwrap_or(Ok(None))
+        }
+    }
+
+    /// Hands out the scripted vqid and replies in order and records the requests made for them.
+    #[derive(Default)]
+    struct FakeTransport {
+        status_vqid: Option<String>,
+        handshakes: std::cell::Cell<usize>,
+        replies: std::cell::RefCell<std::collections::VecDeque<ScriptedReply>>,
+        requests: std::cell::RefCell<Vec<(String, String)>>,
+    }
+
+    impl Transport for FakeTransport {
+        type Reply = ScriptedReply;
+
+        async fn handshake(&self, new_session: bool) -> reqwest::Result<Handshake> {
+            self.handshakes.set(self.handshakes.get() + 1);
+            Ok(Handshake {
+                vqid: self.status_vqid.clone().filter(|_| new_session),
+                headers: HeaderMap::new(),
+            })
+        }
+
+        async fn send(
+            &self,
+            vqid: &str,
+            _headers: &HeaderMap,
+            body: String,
+        ) -> reqwest::Result<ScriptedReply> {
+            self.requests.borrow_mut().push((vqid.to_owned(), body));
+            self.replies
+                .borrow_mut()
+                .pop_front()
+                .ok_or_else(connection_error)
+        }
+    }
+
+    async fn stream_dropping_reply(
+        cli_args: &[&str],
+        first: ScriptedReply,
+        transport: &FakeTransport,
+    ) -> (reqwest::Result<()>, Option<String>, String) {
+        let args = Cli::try_parse_from(["ddgpt"].iter().chain(cli_args).chain(&["hello"])).unwrap();
+        let chat = history(vec![message(ChatRole::User, "hello")]).chat;
+
+        let options = StreamOptions {
+            resume_on_drop: args.resume_on_drop,
+            ..StreamOptions::default()
+        };
 
-    if message.starts_with(b"[DONE]") {
-        let display_message = String::from_utf8_lossy(message);
-        anstream::eprintln!("\n{DIMMED}{display_message}{DIMMED:#}");
-        let _ = std::io::stderr().flush();
-        return;
+        let mut reply = String::new();
+        let (result, vqid) = stream_resuming(
+            transport,
+            first,
+            &OutgoingChat {
+                headers: &HeaderMap::new(),
+                chat: &chat,
+            },
+            &options,
+            &mut reply,
+            &mut |_: &str| {},
+            &mut StreamStats::default(),
+        )
+        .await;
+        (result, vqid, reply)
     }
 
-    let message_deserialized: ChatBotEvent = serde_json::from_slice(message).unwrap_or_else(|a| {
-        let message_printable = String::from_utf8_lossy(message).to_string();
-        panic!(
-            "Chatbot SSE not valid json! {:?}\n    MESSAGE: {}",
-            a, message_printable
-        );
-    });
+    #[tokio::test]
+    async fn dropped_reply_without_resume_keeps_the_partial() {
+        let transport = FakeTransport::default();
+        let first = ScriptedReply::new(vec![event("Hel"), Err(connection_error())]);
+
+        let (result, _, reply) = stream_dropping_reply(&[], first, &transport).await;
 
-    if let Some(chat_message_fragment) = message_deserialized.message {
-        assistant_message.push_str(&chat_message_fragment);
-        print!("{chat_message_fragment}");
-        let _ = std::io::stdout().flush();
+        assert!(result.is_err());
+        assert_eq!(reply, "Hel");
+        assert!(transport.requests.borrow().is_empty());
     }
-}
 
-#[tokio::main]
-async fn main() {
-    let args_parsed = Cli::parse();
-    let ddgpt_config = DDGPTConfigDescription::load()
-        .expect("Could not load / access / initialize the general configuration file");
-    // dbg!(&args_parsed);
+    #[tokio::test]
+    async fn dropped_reply_is_resumed() {
+        let transport = FakeTransport::default();
+        transport
+            .replies
+            .borrow_mut()
+            .push_back(ScriptedReply::new(vec![
+                event("lo"),
+                Ok(Some(b"data: [DONE]\n\n".to_vec())),
+            ]));
+        let first = ScriptedReply::new(vec![event("Hel"), Err(connection_error())]);
 
-    let model_arg = args_parsed.model.unwrap_or(ddgpt_config.default_chatbot);
-    let model = model_arg.to_model();
+        let (result, vqid, reply) =
+            stream_dropping_reply(&["--resume-on-drop"], first, &transport).await;
 
-    anstream::eprintln!(
-        "{DIMMED}Using model: {} ({}){DIMMED:#}\n",
-        model_arg.as_str(),
-        serde_json::to_string(&model).expect("Model doesn't have a valid json representation!")
-    );
-    let _ = std::io::stderr().flush();
+        assert!(result.is_ok());
+        assert_eq!(reply, "Hello");
+        assert_eq!(vqid.as_deref(), Some("next"));
 
-    let query = args_parsed.query.join(" ");
-    let mut chat_history = args_parsed
-        .continue_session
-        .then(|| {
-            if let Some(session_name) = args_parsed.session_name.as_deref() {
-                PastChats::load_session_from_name(session_name)
-            } else {
-                PastChats::load_last()
-            }
-            .expect("Failed to load the previous chat, is the data directoy accessible?")
-        })
-        .flatten()
-        .unwrap_or_else(|| ChatHistory {
-            chat: ChatRequest {
-                model: model,
-                messages: vec![],
+        let requests = transport.requests.borrow();
+        assert_eq!(requests.len(), 1);
+        let (request_vqid, body) = &requests[0];
+        assert_eq!(request_vqid, "next");
+        let resumed: ChatRequest = serde_json::from_str(body).unwrap();
+        assert_eq!(resumed.messages[1].content, "Hel");
+        assert_eq!(resumed.messages[2].content, RESUME_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn resume_request_is_paced() {
+        let transport = FakeTransport::default();
+        transport
+            .replies
+            .borrow_mut()
+            .push_back(ScriptedReply::new(vec![event("lo")]));
+        let first = ScriptedReply::new(vec![event("Hel"), Err(connection_error())]);
+        let chat = history(vec![message(ChatRole::User, "hello")]).chat;
+        let pacer = pacing::RequestPacer::new(Duration::from_secs(60));
+        let options = StreamOptions {
+            resume_on_drop: true,
+            pacer: Some(&pacer),
+            ..StreamOptions::default()
+        };
+
+        let mut reply = String::new();
+        let (result, _) = stream_resuming(
+            &transport,
+            first,
+            &OutgoingChat {
+                headers: &HeaderMap::new(),
+                chat: &chat,
             },
-            next_vqid: String::new(),
-        });
+            &options,
+            &mut reply,
+            &mut |_: &str| {},
+            &mut StreamStats::default(),
+        )
+        .await;
 
-    chat_history.chat.messages.push(ChatMessage {
-        role: ChatRole::User,
-        content: query,
-    });
+        assert!(result.is_ok());
+        assert_eq!(reply, "Hello");
+        // The resume request took the pacer's slot, so the next request has to wait
+        assert!(!pacer.delay_at(Instant::now()).is_zero());
+    }
 
-    let client = Client::builder()
-        .user_agent("curl/7.81.0")
-        .build()
-        .expect("Failed to construct http_client");
+    #[tokio::test]
+    async fn failed_resume_request_keeps_the_partial() {
+        // No scripted reply left, so the resume request itself fails
+        let transport = FakeTransport::default();
+        let first = ScriptedReply::new(vec![event("Hel"), Err(connection_error())]);
 
-    let mut ddg_status_request = client.get("https://duckduckgo.com/duckchat/v1/status");
+        let (result, _, reply) =
+            stream_dropping_reply(&["--resume-on-drop"], first, &transport).await;
 
-    // We need to request a new session ID
-    if chat_history.next_vqid.is_empty() {
-        ddg_status_request = ddg_status_request.header("x-vqd-accept", "1")
+        assert!(result.is_err());
+        assert_eq!(reply, "Hel");
+        assert_eq!(transport.requests.borrow().len(), 1);
     }
 
-    let ddg_status_request = ddg_status_request.build().unwrap();
+    #[tokio::test]
+    async fn html_reply_is_refused_with_its_text() {
+        let page = b"<html>\n  <title>Blocked</title>\n  <body>Unusual traffic</body>\n</html>";
+        let mut reply = ScriptedReply::new(vec![Ok(Some(page.to_vec()))]);
+        reply.content_type = Some("text/html; charset=UTF-8".to_owned());
 
-    // dbg!(&ddg_status_request);
-    let ddg_status_response = client
-        .execute(ddg_status_request)
-        .await
-        .expect("Failed to send status request");
+        let error = check_event_stream(&mut reply).await.unwrap_err();
+        let TurnError::NotAnEventStream {
+            content_type,
+            excerpt,
+        } = &error
+        else {
+            panic!("unexpected error {error:?}");
+        };
+        assert_eq!(content_type, "text/html; charset=UTF-8");
+        assert!(excerpt.contains("<title>Blocked</title> <body>Unusual traffic</body>"));
 
-    // dbg!(&ddg_status_response);
-    // dbg!(&chat_history);
-    // dbg!(&serde_json::to_string(&chat_history).unwrap());
+        let mut reply = ScriptedReply::new(vec![event("Hi")]);
+        reply.content_type = Some("text/event-stream; charset=utf-8".to_owned());
+        check_event_stream(&mut reply).await.unwrap();
+        // Nothing was consumed
+        assert_eq!(reply.chunks.len(), 1);
+    }
 
-    if chat_history.next_vqid.is_empty() {
-        chat_history.next_vqid = ddg_status_response
-            .headers()
-            .get("x-vqd-4")
-            .unwrap()
-            .to_str()
-            .expect("x-vqd-4 ID was not a valid UTF8-String!")
-            .to_string();
+    #[tokio::test]
+    async fn reply_without_vqid_is_not_resumed() {
+        let transport = FakeTransport::default();
+        let mut first = ScriptedReply::new(vec![event("Hel"), Err(connection_error())]);
+        first.vqid = None;
+
+        let (result, vqid, _) =
+            stream_dropping_reply(&["--resume-on-drop"], first, &transport).await;
+
+        assert!(result.is_err());
+        assert_eq!(vqid, None);
+        assert!(transport.requests.borrow().is_empty());
     }
 
-    // dbg!(&chat_history);
-    let ddg_chat_request = client
-        .post(Url::parse("https://duckduckgo.com/duckchat/v1/chat").unwrap())
-        .header(header::ACCEPT, "text/event-stream")
-        .header(header::CONTENT_TYPE, "application/json")
-        // .header(header::COOKIE, value)
-        .header("x-vqd-4", &chat_history.next_vqid)
-        .body(
-            // r#"{"model":"gpt-4o-mini","messages":[{"content": "foobar", "role": "user"}]}"#
-            serde_json::to_string(&chat_history.chat)
-                .expect("Failed to json-serialize the request"),
+    #[test]
+    fn model_requirement_applies_to_its_model_only() {
+        let mut requirements = ModelRequirements::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("dcm=3"));
+        requirements.headers.insert(GPTModelIdent::Claude3, headers);
+
+        assert_eq!(
+            requirements.headers_for(GPTModelIdent::Claude3)[header::COOKIE],
+            "dcm=3"
+        );
+        assert!(requirements.headers_for(GPTModelIdent::GPT4oMini).is_empty());
+    }
+
+    #[test]
+    fn grep_keeps_only_the_sessions_it_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in [("rust", "borrow checker woes"), ("cooking", "pasta recipes")] {
+            let session = history(vec![message(ChatRole::User, content)]);
+            PastChats::save_in(dir.path(), name, &session).unwrap();
+        }
+        // Its name matches, but it is no session
+        std::fs::write(dir.path().join("borrow"), "not json").unwrap();
+        let titles = title::FirstMessage::default();
+
+        let grep = regex::Regex::new("b.rrow").unwrap();
+        let sessions = PastChats::list_in(dir.path(), &titles, Some(&grep)).unwrap();
+        let names: Vec<_> = sessions.iter().map(|session| session.name.as_str()).collect();
+        assert_eq!(names, ["rust"]);
+
+        let grep = regex::Regex::new("^cook").unwrap();
+        let sessions = PastChats::list_in(dir.path(), &titles, Some(&grep)).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].name, "cooking");
+    }
+
+    #[test]
+    fn session_list_json_has_one_object_per_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let session =
+            serde_json::to_string(&history(vec![message(ChatRole::User, "hello")])).unwrap();
+        std::fs::write(dir.path().join("first"), &session).unwrap();
+        std::fs::write(dir.path().join("second"), &session).unwrap();
+        // None of these are sessions, and none may abort the listing
+        std::fs::create_dir(dir.path().join("subdir")).unwrap();
+        std::fs::write(dir.path().join("junk"), "not json").unwrap();
+        std::fs::write(dir.path().join(".first.123.tmp"), &session).unwrap();
+
+        let titles = title::FirstMessage::default();
+        let sessions = PastChats::list_in(dir.path(), &titles, None).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&sessions).unwrap()).unwrap();
+
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            assert!(["first", "second"].contains(&entry["name"].as_str().unwrap()));
+            assert!(entry["updated"].as_u64().unwrap() > 0);
+            assert_eq!(entry["message_count"], 1);
+            assert_eq!(entry["model"], ModelIdentArg::GPT4oMini.as_str());
+            assert_eq!(entry["title"], "hello");
+        }
+    }
+
+    #[test]
+    fn rewind_drops_whole_turns() {
+        let mut messages = vec![
+            message(ChatRole::User, "q1"),
+            message(ChatRole::Assistant, "a1"),
+            message(ChatRole::User, "q2"),
+            message(ChatRole::Assistant, "a2"),
+            message(ChatRole::User, "q3"),
+            message(ChatRole::Assistant, "a3"),
+        ];
+
+        assert_eq!(truncate_turns(&mut messages, 0, 2), 2);
+        assert_eq!(
+            messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(),
+            ["q1", "a1"]
+        );
+
+        // Asking for more turns than there are empties the history
+        assert_eq!(truncate_turns(&mut messages, 0, 5), 1);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn rewind_keeps_pinned_context_ahead_of_a_reply() {
+        // --context-role assistant, so the first message after the context is a reply
+        let mut messages = vec![
+            message(ChatRole::Assistant, "context"),
+            message(ChatRole::Assistant, "a0"),
+            message(ChatRole::User, "q1"),
+            message(ChatRole::Assistant, "a1"),
+        ];
+
+        // a0 would open the conversation without a question before it
+        assert_eq!(truncate_turns(&mut messages, 1, 1), 1);
+        assert_eq!(
+            messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(),
+            ["context"]
+        );
+    }
+
+    #[test]
+    fn rewind_past_the_start_leaves_only_pinned_context() {
+        let mut messages = vec![
+            message(ChatRole::User, "The current date is 2026-10-16."),
+            message(ChatRole::User, "context"),
+            message(ChatRole::User, "q1"),
+            message(ChatRole::Assistant, "a1"),
+        ];
+
+        // The pinned messages are user messages too, but not turns to rewind
+        assert_eq!(truncate_turns(&mut messages, 2, 3), 1);
+        assert_eq!(
+            messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(),
+            ["The current date is 2026-10-16.", "context"]
+        );
+    }
+
+    #[test]
+    fn rewind_keeps_the_original_unless_asked() {
+        let target = |cli_args: &[&str]| {
+            let args = Cli::try_parse_from(["ddgpt", "-c", "-s", "main"].iter().chain(cli_args))
+                .unwrap();
+            save_target(&args)
+                .map(|name| name.map(str::to_owned))
+                .map_err(|e| e.kind())
+        };
+
+        assert_eq!(target(&["hi"]), Ok(Some("main".to_owned())));
+        assert_eq!(
+            target(&["--rewind", "1", "hi"]),
+            Err(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(
+            target(&["--rewind", "1", "--save-as", "side", "hi"]),
+            Ok(Some("side".to_owned()))
+        );
+        assert_eq!(
+            target(&["--rewind", "1", "--branch-as", "side", "hi"]),
+            Ok(Some("side".to_owned()))
+        );
+        assert_eq!(
+            target(&["--rewind", "1", "--in-place", "hi"]),
+            Ok(Some("main".to_owned()))
+        );
+        // Nothing gets dropped from the saved session with --no-append
+        assert_eq!(
+            target(&["--rewind", "1", "--no-append", "hi"]),
+            Ok(Some("main".to_owned()))
+        );
+    }
+
+    #[test]
+    fn newline_join_keeps_args_on_separate_lines() {
+        let args = Cli::try_parse_from(["ddgpt", "--join", "newline", "a", "b"]).unwrap();
+        assert_eq!(assemble_query_with(&args, |_| None).query, "a\nb");
+
+        let args = Cli::try_parse_from(["ddgpt", "a", "b"]).unwrap();
+        assert_eq!(assemble_query_with(&args, |_| None).query, "a b");
+    }
+
+    #[test]
+    fn timestamps_render_in_local_time_or_utc() {
+        let tz = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        // 2024-01-01T22:30:00Z, already the next day two hours east
+        let unix = 1704148200;
+
+        assert_eq!(format_timestamp(unix, &tz, false), "2024-01-02 00:30");
+        assert_eq!(format_timestamp(unix, &tz, true), "2024-01-01T22:30:00Z");
+    }
+
+    #[test]
+    fn context_file_becomes_one_leading_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spec.txt");
+        std::fs::write(&path, "line one\nline two\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let args = Cli::try_parse_from(["ddgpt", "--context-from", path, "hi"]).unwrap();
+        let context = context_message(&args).unwrap();
+        assert_eq!(context.role, ChatRole::User);
+        assert_eq!(context.content, "line one\nline two\n");
+
+        let args = Cli::try_parse_from([
+            "ddgpt",
+            "--context-from",
+            path,
+            "--context-role",
+            "assistant",
+            "hi",
+        ])
+        .unwrap();
+        assert_eq!(context_message(&args).unwrap().role, ChatRole::Assistant);
+
+        let args = Cli::try_parse_from(["ddgpt", "hi"]).unwrap();
+        assert!(context_message(&args).is_none());
+    }
+
+    #[test]
+    fn unnamed_session_strategies_name_sessions() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-06-13T09:05:07+02:00").unwrap();
+
+        let timestamp = UnnamedSessionStrategy::Timestamp;
+        assert_eq!(timestamp.session_name(&now), "20240613-090507");
+        assert_eq!(timestamp.shared_session(&now), None);
+        assert_eq!(
+            UnnamedSessionStrategy::Daily.shared_session(&now).as_deref(),
+            Some("daily-2024-06-13")
+        );
+        assert_eq!(
+            UnnamedSessionStrategy::Fixed.shared_session(&now).as_deref(),
+            Some("unnamed")
+        );
+
+        let config: DDGPTConfigDescription =
+            toml::from_str("unnamed_session_strategy = \"daily\"").unwrap();
+        assert_eq!(config.unnamed_session_strategy, UnnamedSessionStrategy::Daily);
+    }
+
+    #[test]
+    fn project_config_overrides_the_user_config() {
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir(repo.path().join(".git")).unwrap();
+        let subdir = repo.path().join("src");
+        std::fs::create_dir(&subdir).unwrap();
+        std::fs::write(
+            repo.path().join(".ddgpt.toml"),
+            "default_chatbot = \"Claude3\"\n",
         )
-        .build()
         .unwrap();
 
-    // println!("{}", serde_json::to_string_pretty(&chat_request).expect("Failed to json-serialize the request"));
+        let user_config = DDGPTConfigDescription {
+            confirm_above_bytes: Some(1000),
+            ..DDGPTConfigDescription::default()
+        };
+        let project_path = config::find_project_file(&subdir, ".ddgpt.toml").unwrap();
+        let merged = config::overlay_config_file(user_config, &project_path).unwrap();
 
-    // dbg!(&ddg_chat_request);
-    let mut ddg_chat_response = client
-        .execute(ddg_chat_request)
-        .await
-        .expect("Failed to send chat request");
+        assert_eq!(merged.default_chatbot, ModelIdentArg::Claude3);
+        assert_eq!(merged.confirm_above_bytes, Some(1000));
+        // The search stops at the repository root
+        assert_eq!(config::find_project_file(&subdir, ".elsewhere.toml"), None);
+    }
 
-    // DDG still returns 200 even on error ...
-    // if ddg_status_response.status() != 200 {
-    //     eprintln!("Error {:?}", ddg_chat_response.text().await.expect("Failed to fetch message from webserver!"));
-    //     return;
-    // }
+    #[test]
+    fn fresh_conversation_with_date_starts_with_it() {
+        let today = chrono::Local::now().date_naive();
+        let args = Cli::try_parse_from(["ddgpt", "--with-date", "hi"]).unwrap();
 
-    let mut assistant_message = String::new();
-    loop {
-        let mut chunk_parser = ChunkParser {
-            buf: vec![],
-            delim: b"\n\n",
+        let messages = opening_messages(&args, false, today, None);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0]
+            .content
+            .contains(&today.format("%Y-%m-%d").to_string()));
+
+        let args = Cli::try_parse_from(["ddgpt", "hi"]).unwrap();
+        assert!(opening_messages(&args, false, today, None).is_empty());
+        assert_eq!(opening_messages(&args, true, today, None).len(), 1);
+    }
+
+    #[test]
+    fn panic_cleanup_resets_the_styling() {
+        let mut stdout = b"partial reply".to_vec();
+        let mut stderr = Vec::new();
+        panic_cleanup(&mut stdout, &mut stderr);
+
+        assert_eq!(stdout, b"partial reply\x1b[0m");
+        assert_eq!(stderr, b"\x1b[0m");
+    }
+
+    #[test]
+    fn fresh_conversation_starts_with_the_models_prefix() {
+        let today = chrono::Local::now().date_naive();
+        let config: DDGPTConfigDescription = toml::from_str(
+            "[model_system_prefix]\nClaude3 = \"Answer briefly.\"\nGPT4oMini = \"Cite sources.\"",
+        )
+        .unwrap();
+        let args = Cli::try_parse_from(["ddgpt", "--with-date", "hi"]).unwrap();
+
+        let opening = |model| opening_messages(&args, false, today, config.system_prefix(model));
+
+        let claude = opening(ModelIdentArg::Claude3);
+        assert_eq!(claude.len(), 2);
+        assert_eq!(claude[0].content, "Answer briefly.");
+        let gpt = opening(ModelIdentArg::GPT4oMini);
+        assert_eq!(gpt[0].content, "Cite sources.");
+        assert_eq!(config.system_prefix(ModelIdentArg::Llama3), None);
+    }
+
+    #[test]
+    fn show_prints_the_sliced_messages() {
+        let messages: Vec<_> = (0..6)
+            .map(|i| {
+                let role = [ChatRole::User, ChatRole::Assistant][i % 2];
+                message(role, &format!("message {i}"))
+            })
+            .collect();
+
+        let (range, warning) = message_range(messages.len(), Some(2), Some(4));
+        assert_eq!(warning, None);
+        assert_eq!(
+            numbered_transcript(&messages[range.clone()], range.start),
+            "[2] user: message 2\n\n[3] assistant: message 3\n\n[4] user: message 4"
+        );
+
+        assert_eq!(message_range(6, None, None), (0..6, None));
+        let (range, warning) = message_range(6, Some(9), Some(7));
+        assert_eq!(range, 5..6);
+        assert_eq!(
+            warning.as_deref(),
+            Some(
+                "Warning: --from 9 is past the last message, 5, using that instead\n\
+                 Warning: --to 7 is past the last message, 5, using that instead"
+            )
+        );
+        assert_eq!(message_range(6, Some(4), Some(2)).0, 4..4);
+        assert_eq!(message_range(0, Some(1), None), (0..0, None));
+    }
+
+    #[test]
+    fn summary_prompt_keeps_the_latest_messages() {
+        let messages = [
+            message(ChatRole::User, "first question"),
+            message(ChatRole::Assistant, "first answer\n"),
+            message(ChatRole::User, "second question"),
+        ];
+
+        assert_eq!(
+            summary_prompt(&messages, 1000),
+            "Summarize this conversation:\n\n\
+             user: first question\n\nassistant: first answer\n\nuser: second question"
+        );
+        assert_eq!(
+            summary_prompt(&messages, 50),
+            "Summarize this conversation:\n\n[1 earlier message(s) left out]\n\n\
+             assistant: first answer\n\nuser: second question"
+        );
+        // The last message alone is too long, its end survives
+        assert_eq!(
+            summary_prompt(&messages, 8),
+            "Summarize this conversation:\n\n[2 earlier message(s) left out]\n\nquestion"
+        );
+    }
+
+    #[test]
+    fn adjacent_same_role_messages_merge_or_error() {
+        let messages = [
+            message(ChatRole::User, "The current date is 2024-06-13."),
+            message(ChatRole::User, "what day is it?"),
+            message(ChatRole::Assistant, "Thursday"),
+        ];
+
+        let merged = merge_consecutive_roles(&messages, true).unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(
+            merged[0].content,
+            "The current date is 2024-06-13.\nwhat day is it?"
+        );
+        assert_eq!(merged[1].role, ChatRole::Assistant);
+
+        assert_eq!(
+            merge_consecutive_roles(&messages, false).unwrap_err(),
+            ChatRole::User
+        );
+        assert_eq!(merge_consecutive_roles(&messages[1..], false).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn piped_stdin_goes_where_the_query_asks() {
+        let args = Cli::try_parse_from(["ddgpt", "Review", "{stdin}", "carefully"]).unwrap();
+        let query = assemble_query_with(&args, |asked_for| {
+            assert!(asked_for);
+            Some("x = 1\n".to_owned())
+        })
+        .query;
+        assert_eq!(query, "Review x = 1 carefully");
+
+        let args = Cli::try_parse_from(["ddgpt", "Review"]).unwrap();
+        let query = assemble_query_with(&args, |asked_for| {
+            assert!(!asked_for);
+            Some("x = 1\n".to_owned())
+        })
+        .query;
+        assert_eq!(query, "Review\n\nx = 1");
+    }
+
+    #[test]
+    fn generated_session_names_never_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat_history = history(vec![message(ChatRole::User, "hello")]);
+
+        let first = PastChats::save_generated_in(dir.path(), "20240613-120000", &chat_history)
+            .unwrap();
+        let second = PastChats::save_generated_in(dir.path(), "20240613-120000", &chat_history)
+            .unwrap();
+
+        assert_ne!(first, second);
+        let titles = title::FirstMessage::default();
+        assert_eq!(PastChats::list_in(dir.path(), &titles, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn template_sections_become_separate_messages() {
+        let filled = format!(
+            "{}Why does this fail?\n",
+            editor::TEMPLATE
+                .replace("your words.\n", "your words.\nAnswer briefly.\n")
+                .replace("error message.\n", "error message.\nlet x: u8 = 256;\n")
+        );
+        let prompt = editor::parse_template(&filled).unwrap();
+
+        let preamble = template_preamble(&prompt);
+        assert!(preamble.iter().all(|message| message.role == ChatRole::User));
+        assert_eq!(
+            preamble.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(),
+            ["Answer briefly.", "let x: u8 = 256;"]
+        );
+        assert_eq!(prompt.question, "Why does this fail?");
+
+        // Sections left empty are left out
+        let prompt = editor::parse_template("just ask").unwrap();
+        assert!(template_preamble(&prompt).is_empty());
+    }
+
+    #[test]
+    fn prompt_option_is_taken_literally() {
+        let args = Cli::try_parse_from(["ddgpt", "--prompt", "-x"]).unwrap();
+        assert_eq!(assemble_query_with(&args, |_| None).query, "-x");
+
+        let conflict = Cli::try_parse_from(["ddgpt", "--prompt", "a", "b"]).unwrap_err();
+        assert_eq!(conflict.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn oversized_session_is_rotated_down_to_its_pinned_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut chat_history = history(vec![
+            message(ChatRole::User, "the spec"),
+            message(ChatRole::User, "q1"),
+            message(ChatRole::Assistant, &"a".repeat(100)),
+        ]);
+        chat_history.pinned = 1;
+
+        let limit = serde_json::to_string(&chat_history).unwrap().len();
+        assert!(PastChats::rotate_in(dir.path(), "notes", &mut chat_history, limit)
+            .unwrap()
+            .is_none());
+
+        let archive = PastChats::rotate_in(dir.path(), "notes", &mut chat_history, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(archive, dir.path().join("notes.1.archive"));
+        let archived: ChatHistory =
+            serde_json::from_str(&std::fs::read_to_string(&archive).unwrap()).unwrap();
+        assert_eq!(archived.chat.messages.len(), 3);
+
+        PastChats::save_in(dir.path(), "notes", &chat_history).unwrap();
+        let saved: ChatHistory =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("notes")).unwrap())
+                .unwrap();
+        assert_eq!(contents(&saved), ["the spec"]);
+        assert!(saved.next_vqid.is_empty());
+
+        // Archives are numbered, not overwritten
+        chat_history.chat.messages.push(message(ChatRole::User, &"q".repeat(100)));
+        let second = PastChats::rotate_in(dir.path(), "notes", &mut chat_history, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second, dir.path().join("notes.2.archive"));
+    }
+
+    #[test]
+    fn declared_aliases_resolve_exactly() {
+        for (alias, model) in [
+            ("gpt4o", ModelIdentArg::GPT4oMini),
+            ("gpt4", ModelIdentArg::GPT4oMini),
+            ("claude", ModelIdentArg::Claude3),
+            ("llama", ModelIdentArg::Llama3),
+            ("mixtral", ModelIdentArg::Mixtral),
+            ("GPT4", ModelIdentArg::GPT4oMini),
+        ] {
+            assert_eq!(resolve_model(alias).unwrap(), model, "alias {alias}");
+        }
+
+        for model in ModelIdentArg::value_variants() {
+            assert_eq!(resolve_model(model.as_str()).unwrap(), *model);
+        }
+    }
+
+    #[test]
+    fn saved_session_is_redacted_but_the_request_is_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let redactor = redact::Redactor::new(&[r"[\w.+-]+@[\w-]+\.[\w.]+".to_owned()]).unwrap();
+        let chat_history = history(vec![
+            message(ChatRole::User, "write to alice@example.com"),
+            message(ChatRole::Assistant, "Dear Alice"),
+        ]);
+
+        PastChats::save_redacted_in(dir.path(), "notes", &chat_history, &redactor).unwrap();
+
+        let stored = PastChats::load_session_in(dir.path(), "notes").unwrap().unwrap();
+        assert_eq!(contents(&stored), ["write to [REDACTED]", "Dear Alice"]);
+        let request = serde_json::to_string(&chat_history.chat).unwrap();
+        assert!(request.contains("alice@example.com"));
+    }
+
+    #[test]
+    fn save_as_writes_the_exchange_despite_no_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = Cli::try_parse_from(["ddgpt", "--no-append", "--save-as", "foo", "hi"]).unwrap();
+
+        let mut chat_history = history(vec![message(ChatRole::User, "hi")]);
+        record_reply(
+            &mut chat_history,
+            Vec::new(),
+            "hello".to_owned(),
+            false,
+            args.no_append && args.save_as.is_none(),
+        );
+        let name = save_target(&args).unwrap().unwrap();
+        PastChats::save_in(dir.path(), name, &chat_history).unwrap();
+
+        let saved: ChatHistory =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("foo")).unwrap())
+                .unwrap();
+        assert_eq!(contents(&saved), ["hi", "hello"]);
+    }
+
+    #[test]
+    fn ndjson_events_are_written_line_by_line() {
+        let lines = std::cell::RefCell::new(Vec::new());
+        let options = StreamOptions {
+            ndjson: Some(&lines as _),
+            ..StreamOptions::default()
         };
 
-        let messages = match ddg_chat_response.chunk().await.unwrap() {
-            Some(val) => chunk_parser.update(&val),
-            None => break,
+        let chunks: [&[u8]; 4] = [
+            b"data: {\"action\":\"success\",\"created\":1,\"message\":\"Hel\"}\n\nda",
+            b"ta: {\"broken\n\n",
+            b"data: {\"action\":\"success\",\"created\":2,\"message\":\"lo\",\"model\":\"gpt-4o-mini\"}\n\n",
+            b"data: [DONE]\n\n",
+        ];
+        let mut chunk_parser = ChunkParser::new(b"\n\n");
+        for chunk in chunks {
+            handle_chunk(
+                &mut chunk_parser,
+                chunk,
+                &options,
+                &mut |_: &str| {},
+                &mut StreamStats::default(),
+            );
+        }
+
+        let lines = String::from_utf8(lines.into_inner()).unwrap();
+        let lines: Vec<serde_json::Value> =
+            lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["message"], "Hel");
+        assert_eq!(lines[1]["message"], "lo");
+        assert_eq!(lines[1]["model"], "gpt-4o-mini");
+        assert_eq!(lines[2], serde_json::json!({ "type": "done" }));
+    }
+
+    #[test]
+    fn dumped_events_match_the_parsed_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events");
+        let dump = std::fs::File::create(&path).unwrap();
+        let options = StreamOptions {
+            event_dump: Some(&dump),
+            ..StreamOptions::default()
         };
 
-        for message in messages {
-            display_message_fragment(&mut assistant_message, &message);
+        let chunks: [&[u8]; 3] = [
+            b"data: {\"action\":\"success\",\"crea",
+            b"ted\":1,\"message\":\"Hi\"}\n\ndata: {\"broken\n\n",
+            b"data: [DONE]\n\n",
+        ];
+        let mut expected = Vec::new();
+        let mut parser = ChunkParser::new(b"\n\n");
+        let mut dumping_parser = ChunkParser::new(b"\n\n");
+        let mut reply = String::new();
+        for chunk in chunks {
+            for event in parser.update(chunk).unwrap() {
+                expected.extend(event);
+                expected.extend_from_slice(b"\n\n");
+            }
+            handle_chunk(
+                &mut dumping_parser,
+                chunk,
+                &options,
+                &mut |fragment: &str| reply.push_str(fragment),
+                &mut StreamStats::default(),
+            );
         }
+
+        assert_eq!(reply, "Hi");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
     }
 
-    if assistant_message != "" {
-        chat_history.next_vqid = ddg_chat_response
-            .headers()
-            .get("x-vqd-4")
-            .unwrap()
-            .to_str()
-            .expect("x-vqd-4 ID was not a valid UTF8-String!")
-            .to_string();
+    #[test]
+    fn replayed_dump_reconstructs_the_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events");
+        let dump = std::fs::File::create(&path).unwrap();
+        let options = StreamOptions {
+            event_dump: Some(&dump),
+            ..StreamOptions::default()
+        };
+        // The second event spans two lines
+        let chunks: [&[u8]; 3] = [
+            b"data: {\"action\":\"success\",\"created\":1,\"message\":\"Hel\"}\n\nda",
+            b"ta: {\"action\":\"success\",\n\"created\":1,\"message\":\"lo\\nthere\"}\n\n",
+            b"data: {\"broken\n\ndata: [DONE]\n\n",
+        ];
+        let mut chunk_parser = ChunkParser::new(b"\n\n");
+        let mut live = String::new();
+        let mut live_stats = StreamStats::default();
+        for chunk in chunks {
+            handle_chunk(
+                &mut chunk_parser,
+                chunk,
+                &options,
+                &mut |fragment: &str| live.push_str(fragment),
+                &mut live_stats,
+            );
+        }
+        drop(dump);
 
-        chat_history.chat.messages.push(ChatMessage {
-            role: ChatRole::Assistant,
-            content: assistant_message,
-        });
+        let mut shown = String::new();
+        let (reply, stats) = replay_events(
+            &std::fs::read(&path).unwrap(),
+            &StreamOptions::default(),
+            &mut |fragment: &str| shown.push_str(fragment),
+        );
+        assert_eq!(reply, "Hello\nthere");
+        assert_eq!(reply, live);
+        assert_eq!(shown, reply);
+        assert_eq!((stats.valid_events, stats.malformed_events), (2, 1));
+    }
 
-        PastChats::save(
-            args_parsed.session_name.as_deref().unwrap_or("foobar"),
-            &chat_history,
-        )
-        .expect("Failed to save the chat!");
+    #[test]
+    fn escaped_newline_only_splits_the_reply_under_unescape() {
+        let reply_lines = |cli_args: &[&str]| {
+            let args =
+                Cli::try_parse_from(["ddgpt"].iter().chain(cli_args).chain(&["hi"])).unwrap();
+            rework_reply(&args, "line1\\nline2".to_owned()).lines().count()
+        };
+
+        assert_eq!(reply_lines(&["--unescape"]), 2);
+        assert_eq!(reply_lines(&[]), 1);
+    }
+
+    #[test]
+    fn explicit_model_is_remembered_only_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = Cli::try_parse_from(["ddgpt", "-m", "claude3", "hi"]).unwrap();
+        let default = DDGPTConfigDescription::load_in(dir.path()).unwrap().default_chatbot;
+        assert_ne!(default, ModelIdentArg::Claude3);
+
+        assert!(!remember_model_in(dir.path(), false, args.model).unwrap());
+        let persisted = DDGPTConfigDescription::load_in(dir.path()).unwrap();
+        assert_eq!(persisted.default_chatbot, default);
+
+        // Without -m the default model is implicit, nothing to remember
+        let implicit = Cli::try_parse_from(["ddgpt", "hi"]).unwrap();
+        assert!(!remember_model_in(dir.path(), true, implicit.model).unwrap());
+
+        assert!(remember_model_in(dir.path(), true, args.model).unwrap());
+        let persisted = DDGPTConfigDescription::load_in(dir.path()).unwrap();
+        assert_eq!(persisted.default_chatbot, ModelIdentArg::Claude3);
     }
-}
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use strsim::{jaro, jaro_winkler, normalized_damerau_levenshtein, normalized_levenshtein};
+    #[test]
+    fn model_env_var_stands_in_for_an_absent_flag() {
+        let parse = |args: &[&str]| {
+            let matches = Cli::command_with_env().try_get_matches_from(args).unwrap();
+            Cli::from_matches_with_env(&matches).unwrap()
+        };
+        // No other test parses with the environment, so it's only seen here
+        std::env::set_var("DDGPT_MODEL", "claude");
+        let from_env = parse(&["ddgpt", "hi"]);
+        let from_flag = parse(&["ddgpt", "-m", "llama3", "hi"]);
+        std::env::remove_var("DDGPT_MODEL");
+
+        assert_eq!(from_env.model, Some(ModelIdentArg::Claude3));
+        assert_eq!(from_flag.model, Some(ModelIdentArg::Llama3));
+        assert_eq!(parse(&["ddgpt", "hi"]).model, None);
+
+        // Only a model passed with -m is one for remember_last_model to persist
+        assert_eq!(from_env.explicit_model(), None);
+        assert_eq!(from_flag.explicit_model(), Some(ModelIdentArg::Llama3));
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!remember_model_in(dir.path(), true, from_env.explicit_model()).unwrap());
+        assert!(remember_model_in(dir.path(), true, from_flag.explicit_model()).unwrap());
+    }
+
+    #[test]
+    fn preview_sums_up_the_request() {
+        let chat = ChatRequest {
+            model: GPTModelIdent::Mixtral,
+            messages: vec![
+                message(ChatRole::User, "What does this do?\nfn main() {}"),
+                message(ChatRole::Assistant, "Nothing."),
+                message(ChatRole::User, &"x".repeat(70)),
+            ],
+        };
+
+        assert_eq!(
+            preview_summary(&chat),
+            format!(
+                "Preview of the request to mistralai/Mixtral-8x7B-Instruct-v0.1:
+  [0] user, 31 chars: What does this do?…
+  [1] assistant, 8 chars: Nothing.
+  [2] user, 70 chars: {}…
+user: 2 message(s), 101 chars
+assistant: 1 message(s), 8 chars
+Total: 109 chars, about 28 tokens of the ~25000 mistralai/Mixtral-8x7B-Instruct-v0.1 handles well",
+                "x".repeat(60)
+            )
+        );
+    }
+
+    #[test]
+    fn context_warning_depends_on_the_model() {
+        let long = [message(ChatRole::User, &"a".repeat(150_000))];
+        let warning = context_warning(GPTModelIdent::Mixtral, &long).unwrap();
+        assert!(warning.contains("150000 characters"));
+        assert!(warning.contains("--rewind"));
+
+        let shorter = [message(ChatRole::User, &"a".repeat(120_000))];
+        assert_eq!(context_warning(GPTModelIdent::Claude3, &shorter), None);
+    }
+
+    #[test]
+    fn missing_named_session_fails_strict_continue_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let strict = PastChats::load_continued_in(dir.path(), Some("notes"), false);
+        assert!(matches!(
+            strict,
+            Err(ConfigError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+        let soft = PastChats::load_continued_in(dir.path(), Some("notes"), true);
+        assert!(soft.unwrap().is_none());
+
+        // Once it exists, both continue it
+        let chat_history = history(vec![message(ChatRole::User, "q1")]);
+        PastChats::save_in(dir.path(), "notes", &chat_history).unwrap();
+        for soft in [false, true] {
+            let (name, loaded) = PastChats::load_continued_in(dir.path(), Some("notes"), soft)
+                .unwrap()
+                .unwrap();
+            assert_eq!(name, "notes");
+            assert_eq!(contents(&loaded), ["q1"]);
+        }
+    }
+
+    #[test]
+    fn too_many_sessions_warn() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.path().join(name), "not parsed").unwrap();
+        }
+        std::fs::write(dir.path().join("a.1.archive"), "").unwrap();
+
+        assert_eq!(session_count_warning(dir.path(), 3), None);
+        let warning = session_count_warning(dir.path(), 2).unwrap();
+        assert!(warning.contains("3 sessions"), "{warning}");
+    }
+
+    #[test]
+    fn session_names_differing_in_case_are_one_session_when_normalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let chat_history = history(vec![message(ChatRole::User, "q1")]);
+
+        let titles = title::FirstMessage::default();
+        let name = |raw| stored_session_name(raw, true);
+        PastChats::save_in(dir.path(), &name("Foo"), &chat_history).unwrap();
+        PastChats::save_in(dir.path(), &name("foo"), &chat_history).unwrap();
+        assert_eq!(PastChats::list_in(dir.path(), &titles, None).unwrap().len(), 1);
+        assert!(PastChats::load_session_in(dir.path(), &name("FOO")).unwrap().is_some());
+
+        let dir = tempfile::tempdir().unwrap();
+        let name = |raw| stored_session_name(raw, false);
+        PastChats::save_in(dir.path(), &name("Foo"), &chat_history).unwrap();
+        PastChats::save_in(dir.path(), &name("foo"), &chat_history).unwrap();
+        assert_eq!(PastChats::list_in(dir.path(), &titles, None).unwrap().len(), 2);
+
+        let mut args = Cli::try_parse_from(["ddgpt", "-c", "-s", "Notes", "hi"]).unwrap();
+        normalize_session_names(&mut args, true);
+        assert_eq!(args.session_name.as_deref(), Some("notes"));
+    }
+
+    #[test]
+    fn continued_session_is_saved_back_to_its_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut chat_history = history(vec![message(ChatRole::User, "first")]);
+        PastChats::save_in(dir.path(), "20261016-093000", &chat_history).unwrap();
+        std::fs::write(dir.path().join(".20261016-093000.tmp"), "half written").unwrap();
+
+        let (name, mut loaded) = PastChats::load_last_in(dir.path()).unwrap().unwrap();
+        assert_eq!(name, "20261016-093000");
+        loaded.chat.messages.push(message(ChatRole::Assistant, "reply"));
+        PastChats::save_in(dir.path(), &name, &loaded).unwrap();
+
+        let titles = title::FirstMessage::default();
+        assert_eq!(PastChats::list_in(dir.path(), &titles, None).unwrap().len(), 1);
+        chat_history.chat.messages.push(message(ChatRole::Assistant, "reply"));
+        let (_, reloaded) = PastChats::load_last_in(dir.path()).unwrap().unwrap();
+        assert_eq!(contents(&reloaded), contents(&chat_history));
+    }
+
+    // use strsim::{jaro, jaro_winkler, normalized_damerau_levenshtein, normalized_levenshtein};
 
 //     #[test]
 //     fn test_jaro() {
@@ -592,4 +5740,4 @@ async fn main() {
 //         dbg!(normalized_damerau_levenshtein("gpt", "gptclaud"));
 //         dbg!(normalized_damerau_levenshtein("gpt", "gtp"));
 //     }
-// }
+}
@@ -0,0 +1,633 @@
+use std::{
+    io::Write,
+    process::{Child, ChildStdin, Command, Stdio},
+};
+
+/// Somewhere a reply goes as it streams in.
+pub trait ReplySink {
+    /// What to call the sink when it fails.
+    fn name(&self) -> &str;
+    fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()>;
+    /// Called once the reply is complete.
+    fn finish(self: Box<Self>) -> std::io::Result<()>;
+}
+
+/// Hands each fragment to every sink. A sink that fails is dropped with a warning,
+/// the others carry on.
+#[derive(Default)]
+pub struct FanOut {
+    sinks: Vec<Box<dyn ReplySink>>,
+}
+
+impl FanOut {
+    pub fn push(&mut self, sink: impl ReplySink + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn write_fragment(&mut self, fragment: &str) {
+        self.sinks
+            .retain_mut(|sink| match sink.write_fragment(fragment) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Warning: failed to write the reply to {}: {e}", sink.name());
+                    false
+                }
+            });
+    }
+
+    pub fn finish(self) {
+        for sink in self.sinks {
+            let name = sink.name().to_owned();
+            if let Err(e) = sink.finish() {
+                eprintln!("Warning: failed to write the reply to {name}: {e}");
+            }
+        }
+    }
+}
+
+/// A plain writer as a sink, flushed after every fragment so the reply shows as it arrives.
+/// Line breaks the reply ends on are held back, it's finished with exactly one `\n` instead,
+/// or none at all without `trailing_newline`.
+pub struct WriterSink<W: Write> {
+    name: String,
+    inner: W,
+    trailing_newline: bool,
+    pending_breaks: String,
+    written_any: bool,
+}
+
+impl<W: Write> WriterSink<W> {
+    pub fn new(name: impl Into<String>, inner: W, trailing_newline: bool) -> Self {
+        WriterSink {
+            name: name.into(),
+            inner,
+            trailing_newline,
+            pending_breaks: String::new(),
+            written_any: false,
+        }
+    }
+}
+
+impl<W: Write> ReplySink for WriterSink<W> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        let content = fragment.trim_end_matches(['\r', '\n']);
+        if !content.is_empty() {
+            self.inner.write_all(self.pending_breaks.as_bytes())?;
+            self.pending_breaks.clear();
+            self.inner.write_all(content.as_bytes())?;
+            self.written_any = true;
+        }
+        self.pending_breaks.push_str(&fragment[content.len()..]);
+        self.inner.flush()
+    }
+
+    fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+        if self.trailing_newline && self.written_any {
+            self.inner.write_all(b"\n")?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Streams text into a writer while normalizing line endings to `\n`.
+/// Trailing newlines are held back until more content arrives,
+/// so [`NormalizedWriter::finish`] can end the output with exactly one;
+/// leading newlines are dropped entirely.
+pub struct NormalizedWriter<W: Write> {
+    inner: W,
+    pending_cr: bool,
+    pending_newlines: usize,
+    written_any: bool,
+}
+
+impl<W: Write> NormalizedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        NormalizedWriter {
+            inner,
+            pending_cr: false,
+            pending_newlines: 0,
+            written_any: false,
+        }
+    }
+
+    pub fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        let mut normalized = String::with_capacity(fragment.len());
+        for c in fragment.chars() {
+            // A '\r' may be the first half of a "\r\n" split across fragments
+            if std::mem::take(&mut self.pending_cr) && c != '\n' {
+                self.pending_newlines += 1;
+            }
+
+            match c {
+                '\r' => self.pending_cr = true,
+                '\n' => self.pending_newlines += 1,
+                c => {
+                    if self.written_any {
+                        normalized.extend(std::iter::repeat_n('\n', self.pending_newlines));
+                    }
+                    self.pending_newlines = 0;
+                    self.written_any = true;
+                    normalized.push(c);
+                }
+            }
+        }
+
+        self.inner.write_all(normalized.as_bytes())
+    }
+
+    /// Terminates the output with a single newline and flushes it.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if self.written_any {
+            self.inner.write_all(b"\n")?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// The pager to run for `--pager`, from `$PAGER` or `less -R` if that's unset or blank.
+/// The value is split on whitespace, there is no shell to interpret it.
+pub fn pager_command(pager_var: Option<&str>) -> Vec<String> {
+    let command: Vec<String> = pager_var
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect();
+    if command.is_empty() {
+        return vec!["less".to_owned(), "-R".to_owned()];
+    }
+    command
+}
+
+impl<W: Write> ReplySink for NormalizedWriter<W> {
+    fn name(&self) -> &str {
+        "the output file"
+    }
+
+    fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        NormalizedWriter::write_fragment(self, fragment)
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        NormalizedWriter::finish(*self).map(|_| ())
+    }
+}
+
+/// Writes to a pager until it goes away, say because the user quit it early,
+/// silently dropping whatever comes after.
+pub struct PagerWriter<W: Write> {
+    inner: W,
+    closed: bool,
+}
+
+impl<W: Write> PagerWriter<W> {
+    pub fn new(inner: W) -> Self {
+        PagerWriter {
+            inner,
+            closed: false,
+        }
+    }
+
+    pub fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        match self
+            .inner
+            .write_all(fragment.as_bytes())
+            .and_then(|()| self.inner.flush())
+        {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// A running pager process the reply streams into.
+pub struct Pager {
+    child: Child,
+    stdin: PagerWriter<ChildStdin>,
+}
+
+impl Pager {
+    pub fn spawn(command: &[String]) -> std::io::Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| std::io::Error::other("empty pager command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("the pager's stdin was piped");
+        Ok(Pager {
+            child,
+            stdin: PagerWriter::new(stdin),
+        })
+    }
+
+    pub fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        self.stdin.write_fragment(fragment)
+    }
+
+    /// Closes the pager's input and waits for the user to quit it.
+    pub fn finish(self) -> std::io::Result<()> {
+        let Pager { mut child, stdin } = self;
+        drop(stdin);
+        child.wait().map(|_| ())
+    }
+}
+
+impl ReplySink for Pager {
+    fn name(&self) -> &str {
+        "the pager"
+    }
+
+    fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        Pager::write_fragment(self, fragment)
+    }
+
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        Pager::finish(*self)
+    }
+}
+
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Stands in for the reply while it only goes to a file, for `--reply-only-to-file`:
+/// a spinner with a running count on `progress`, if there's a terminal to draw it on,
+/// and once done a line saying where the reply went on `summary`.
+pub struct ProgressSink<S: Write, P: Write> {
+    summary: S,
+    progress: Option<P>,
+    destination: String,
+    chars: usize,
+}
+
+impl<S: Write, P: Write> ProgressSink<S, P> {
+    pub fn new(summary: S, progress: Option<P>, destination: impl Into<String>) -> Self {
+        ProgressSink {
+            summary,
+            progress,
+            destination: destination.into(),
+            chars: 0,
+        }
+    }
+}
+
+impl<S: Write, P: Write> ReplySink for ProgressSink<S, P> {
+    fn name(&self) -> &str {
+        "the progress display"
+    }
+
+    fn write_fragment(&mut self, fragment: &str) -> std::io::Result<()> {
+        self.chars += fragment.chars().count();
+        if let Some(progress) = self.progress.as_mut() {
+            let frame = SPINNER_FRAMES[self.chars % SPINNER_FRAMES.len()];
+            write!(progress, "\r{frame} receiving, {} chars", self.chars)?;
+            progress.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+        if let Some(progress) = self.progress.as_mut() {
+            // Clears the spinner's line
+            write!(progress, "\r\x1b[K")?;
+            progress.flush()?;
+        }
+        writeln!(self.summary, "saved {} chars to {}", self.chars, self.destination)?;
+        self.summary.flush()
+    }
+}
+
+/// Writes a prompt with its label, for `--echo`.
+pub fn echo_prompt(out: &mut impl Write, prompt: &str) -> std::io::Result<()> {
+    writeln!(out, "you: {prompt}")?;
+    out.flush()
+}
+
+/// Turns the escape sequences over-escaping models leave in their replies
+/// (`\n`, `\r`, `\t`, `\"` and `\\`) back into the characters they stand for.
+/// Any other backslash is kept as is, and an escaped backslash never starts another escape,
+/// so `\\n` becomes a backslash followed by `n` rather than a newline.
+/// Code blocks and inline code are left alone, escapes there are part of the code.
+pub fn unescape(text: &str) -> String {
+    use pulldown_cmark::{Event, Parser, Tag};
+
+    let mut unescaped = String::with_capacity(text.len());
+    let mut plain_start = 0;
+    for (event, range) in Parser::new(text).into_offset_iter() {
+        if let Event::Start(Tag::CodeBlock(_)) | Event::Code(_) = event {
+            unescaped.push_str(&unescape_plain(&text[plain_start..range.start]));
+            unescaped.push_str(&text[range.clone()]);
+            plain_start = range.end;
+        }
+    }
+    unescaped.push_str(&unescape_plain(&text[plain_start..]));
+    unescaped
+}
+
+/// [`unescape`] for text without any code in it.
+fn unescape_plain(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.clone().next() {
+            Some(escaped @ ('n' | 'r' | 't' | '"' | '\\')) => {
+                chars.next();
+                unescaped.push(match escaped {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    other => other,
+                });
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Reduces markdown to its plain text content, dropping emphasis,
+/// code fences, heading markers and link targets.
+pub fn strip_markdown(markdown: &str) -> String {
+    use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+    let mut text = String::with_capacity(markdown.len());
+    let mut list_counters: Vec<Option<u64>> = Vec::new();
+
+    for event in Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES) {
+        match event {
+            Event::Text(t) | Event::Code(t) | Event::InlineMath(t) | Event::DisplayMath(t) => {
+                text.push_str(&t)
+            }
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Start(Tag::List(first_number)) => {
+                // Nested lists start right after their parent item's text
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                list_counters.push(first_number);
+            }
+            Event::End(TagEnd::List(_)) => {
+                list_counters.pop();
+                text.push('\n');
+            }
+            Event::Start(Tag::Item) => {
+                let indent = "  ".repeat(list_counters.len().saturating_sub(1));
+                match list_counters.last_mut() {
+                    Some(Some(n)) => {
+                        text.push_str(&format!("{indent}{n}. "));
+                        *n += 1;
+                    }
+                    _ => text.push_str(&format!("{indent}- ")),
+                }
+            }
+            Event::End(TagEnd::Item | TagEnd::TableRow | TagEnd::TableHead) => text.push('\n'),
+            Event::End(TagEnd::TableCell) => text.push('\t'),
+            Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::CodeBlock) => {
+                text.push_str("\n\n")
+            }
+            _ => {}
+        }
+    }
+
+    // Block ends each push their own separators, collapse the pile-ups
+    let mut collapsed = String::with_capacity(text.len());
+    let mut newlines = 0;
+    for c in text.trim().chars() {
+        if c == '\n' {
+            newlines += 1;
+            if newlines > 2 {
+                continue;
+            }
+        } else {
+            newlines = 0;
+        }
+        collapsed.push(c);
+    }
+    collapsed
+}
+
+/// A line by line diff turning `old` into `new`, each line marked with
+/// `-` (only in `old`), `+` (only in `new`) or a space (in both).
+pub fn line_diff(old: &str, new: &str) -> String {
+    use similar::{ChangeTag, TextDiff};
+
+    // Whether a reply ends on a newline is no difference worth showing
+    let old = format!("{}\n", old.trim_end_matches(['\r', '\n']));
+    let new = format!("{}\n", new.trim_end_matches(['\r', '\n']));
+
+    let mut diff = String::new();
+    for change in TextDiff::from_lines(&old, &new).iter_all_changes() {
+        let sign = match change.tag() {
+            ChangeTag::Delete => '-',
+            ChangeTag::Insert => '+',
+            ChangeTag::Equal => ' ',
+        };
+        diff.push(sign);
+        diff.push(' ');
+        diff.push_str(change.value().trim_end_matches(['\r', '\n']));
+        diff.push('\n');
+    }
+    diff
+}
+
+/// Whether `line` opens a sources section, like "Sources:", "**References:**" or "## Sources".
+fn is_sources_heading(line: &str) -> bool {
+    let line = line.trim();
+    let heading = line.starts_with('#');
+    let label = line.trim_start_matches('#').trim().trim_matches(['*', '_']).trim();
+    let label = label.to_lowercase();
+    ["sources", "references"].iter().any(|name| {
+        label.starts_with(&format!("{name}:")) || (heading && label == *name)
+    })
+}
+
+/// Splits the reply's trailing sources section, everything from the last line opening one,
+/// off the answer. The answer comes back whole if there is none.
+pub fn split_sources(reply: &str) -> (&str, Option<&str>) {
+    let mut start = None;
+    let mut offset = 0;
+    for line in reply.split_inclusive('\n') {
+        if is_sources_heading(line) {
+            start = Some(offset);
+        }
+        offset += line.len();
+    }
+
+    match start {
+        Some(start) => (reply[..start].trim_end(), Some(reply[start..].trim())),
+        None => (reply, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_marks_changed_lines() {
+        let old = "Paris is the capital.\nIt has 2.1M people.\nBye";
+        let new = "Paris is the capital.\nIt has 2.2M people.\nBye\n";
+        assert_eq!(
+            line_diff(old, new),
+            "  Paris is the capital.\n- It has 2.1M people.\n+ It has 2.2M people.\n  Bye\n"
+        );
+        assert_eq!(line_diff("same\n", "same\n"), "  same\n");
+    }
+
+    #[test]
+    fn trailing_sources_are_split_off() {
+        let reply = "Rust 1.0 came out in 2015.\n\n**Sources:**\n- [1] blog.rust-lang.org\n";
+        assert_eq!(
+            split_sources(reply),
+            (
+                "Rust 1.0 came out in 2015.",
+                Some("**Sources:**\n- [1] blog.rust-lang.org")
+            )
+        );
+
+        let reply = "Answer\n\n## References\n1. a paper";
+        assert_eq!(split_sources(reply), ("Answer", Some("## References\n1. a paper")));
+
+        // Mentioning sources isn't a section of them
+        let reply = "Check the sources: they disagree.\nSourcesless text";
+        assert_eq!(split_sources(reply), (reply, None));
+    }
+
+    #[test]
+    fn reply_only_to_file_prints_just_the_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reply.md");
+        let mut stdout = Vec::new();
+
+        let mut progress = Box::new(ProgressSink::new(&mut stdout, None::<Vec<u8>>, "reply.md"));
+        let mut file = Box::new(NormalizedWriter::new(std::fs::File::create(&path).unwrap()));
+        for fragment in ["Hello", ", ", "world"] {
+            progress.write_fragment(fragment).unwrap();
+            file.write_fragment(fragment).unwrap();
+        }
+        progress.finish().unwrap();
+        file.finish().unwrap();
+
+        assert_eq!(String::from_utf8(stdout).unwrap(), "saved 12 chars to reply.md\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Hello, world\n");
+    }
+
+    #[test]
+    fn output_file_line_endings_are_normalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reply.md");
+
+        let file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        let mut writer = NormalizedWriter::new(file);
+        // "\r\n" split across fragments, a lone '\r' and a pile of trailing newlines
+        for fragment in ["\nline one\r", "\nline two\r\n\r\n", "line three\rline four\n\n\n"] {
+            writer.write_fragment(fragment).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "line one\nline two\n\nline three\nline four\n"
+        );
+    }
+
+    #[test]
+    fn empty_reply_writes_nothing() {
+        let writer = NormalizedWriter::new(Vec::new());
+        assert!(writer.finish().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stdout_ends_with_one_newline_unless_disabled() {
+        for (trailing_newline, expected) in [(true, "Hi\n\nthere\n"), (false, "Hi\n\nthere")] {
+            let mut written = Vec::new();
+            let mut sink = Box::new(WriterSink::new("stdout", &mut written, trailing_newline));
+            for fragment in ["Hi\n\nthere", "\n", "\r\n"] {
+                sink.write_fragment(fragment).unwrap();
+            }
+            sink.finish().unwrap();
+            assert_eq!(String::from_utf8(written).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn pager_defaults_to_less() {
+        assert_eq!(pager_command(None), ["less", "-R"]);
+        assert_eq!(pager_command(Some("  ")), ["less", "-R"]);
+        assert_eq!(pager_command(Some("most -s")), ["most", "-s"]);
+    }
+
+    struct HungUpPipe {
+        writes: usize,
+    }
+
+    impl Write for HungUpPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn quitting_the_pager_early_is_not_an_error() {
+        let mut writer = PagerWriter::new(HungUpPipe { writes: 0 });
+        writer.write_fragment("first").unwrap();
+        writer.write_fragment("second").unwrap();
+        assert_eq!(writer.inner.writes, 1);
+    }
+
+    #[test]
+    fn echoed_prompt_is_labeled() {
+        let mut stream = Vec::new();
+        echo_prompt(&mut stream, "what time is it?").unwrap();
+        assert_eq!(stream, b"you: what time is it?\n");
+    }
+
+    #[test]
+    fn escapes_become_characters() {
+        assert_eq!(unescape("line1\\nline2"), "line1\nline2");
+        assert_eq!(unescape("say \\\"hi\\\"\\tnow"), "say \"hi\"\tnow");
+        // Escaped backslashes don't start another escape, unknown ones stay
+        assert_eq!(unescape(r"C:\\new \d+ end\"), r"C:\new \d+ end\");
+    }
+
+    #[test]
+    fn code_keeps_its_escapes() {
+        let reply = "Like so:\\tdone\n\n```c\nprintf(\"a\\n\");\nchar *p = \"C:\\\\dir\";\n```\n\n\
+                     Or `puts(\"\\n\")`, one\\ntwo.";
+        assert_eq!(
+            unescape(reply),
+            "Like so:\tdone\n\n```c\nprintf(\"a\\n\");\nchar *p = \"C:\\\\dir\";\n```\n\n\
+             Or `puts(\"\\n\")`, one\ntwo."
+        );
+    }
+
+    #[test]
+    fn markdown_syntax_is_stripped() {
+        assert_eq!(strip_markdown("**bold** and `code`"), "bold and code");
+        assert_eq!(
+            strip_markdown("# Title\n\n1. one\n2. two\n\n```sh\nls\n```"),
+            "Title\n\n1. one\n2. two\n\nls"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Keeps successive requests from one process at least `min_interval` apart,
+/// so loops over turns or models don't hammer DDG.
+#[derive(Debug)]
+pub struct RequestPacer {
+    min_interval: Duration,
+    /// When the last request was let through, or is due to be
+    last: Mutex<Option<Instant>>,
+}
+
+impl RequestPacer {
+    pub fn new(min_interval: Duration) -> Self {
+        RequestPacer {
+            min_interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// How long a request wanting to go out at `now` has to wait, claiming its slot.
+    pub fn delay_at(&self, now: Instant) -> Duration {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let start = match *last {
+            Some(last) => now.max(last + self.min_interval),
+            None => now,
+        };
+        *last = Some(start);
+        start - now
+    }
+
+    /// Waits until the next request may go out.
+    pub async fn wait(&self) {
+        let delay = self.delay_at(Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn back_to_back_requests_are_spaced_out() {
+        let pacer = RequestPacer::new(Duration::from_millis(500));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(pacer.delay_at(at(0)), Duration::ZERO);
+        // Right after the first, and while the second is still waiting for its slot
+        assert_eq!(pacer.delay_at(at(100)), Duration::from_millis(400));
+        assert_eq!(pacer.delay_at(at(200)), Duration::from_millis(800));
+        // Long after, nothing to wait for
+        assert_eq!(pacer.delay_at(at(5000)), Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_interval_never_waits() {
+        let pacer = RequestPacer::new(Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(pacer.delay_at(now), Duration::ZERO);
+        assert_eq!(pacer.delay_at(now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use tokio::net::{lookup_host, TcpStream};
+
+pub const PROBE_ADDRESS: &str = "duckduckgo.com:443";
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// Checks that `address` resolves and accepts a TCP connection within `timeout`,
+/// so being offline shows up right away instead of as a slow request timeout.
+pub async fn probe(address: &str, timeout: Duration) -> std::io::Result<()> {
+    let connect = async {
+        let mut last_error = None;
+        for addr in lookup_host(address).await? {
+            match TcpStream::connect(addr).await {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| std::io::Error::other(format!("{address} resolved to nothing"))))
+    };
+
+    tokio::time::timeout(timeout, connect)
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unreachable_address_fails_fast() {
+        let start = Instant::now();
+        // TEST-NET-1, reserved for documentation and never routed
+        let result = probe("192.0.2.1:443", Duration::from_millis(200)).await;
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn listening_address_passes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        probe(&address, PROBE_TIMEOUT).await.unwrap();
+    }
+}
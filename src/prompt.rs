@@ -0,0 +1,213 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use encoding_rs::Encoding;
+
+/// Where in the query piped stdin goes, instead of after it.
+pub const STDIN_PLACEHOLDER: &str = "{stdin}";
+
+/// Puts `stdin` in place of every [`STDIN_PLACEHOLDER`] in `query`,
+/// or appends it as its own paragraph if there is none.
+pub fn insert_stdin(query: &str, stdin: &str) -> String {
+    let stdin = stdin.strip_suffix('\n').unwrap_or(stdin);
+    if query.contains(STDIN_PLACEHOLDER) {
+        query.replace(STDIN_PLACEHOLDER, stdin)
+    } else if stdin.trim().is_empty() {
+        query.to_owned()
+    } else if query.is_empty() {
+        stdin.to_owned()
+    } else {
+        format!("{query}\n\n{stdin}")
+    }
+}
+
+/// Reads a file as text in `encoding`, UTF-8 unless given.
+/// Bytes that aren't valid in the encoding fail the read instead of being replaced.
+pub fn read_text(path: &Path, encoding: Option<&'static Encoding>) -> std::io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    let encoding = encoding.unwrap_or(encoding_rs::UTF_8);
+    match encoding.decode_without_bom_handling_and_without_replacement(&bytes) {
+        Some(text) => Ok(text.into_owned()),
+        None if encoding == encoding_rs::UTF_8 => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not valid UTF-8, --input-encoding can name the encoding it's in",
+        )),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("not valid {}", encoding.name()),
+        )),
+    }
+}
+
+/// Wraps a file's content in a markdown code fence labeled with its path.
+/// The fence is made longer than any backtick run inside the content,
+/// so included markdown files can't terminate it early.
+pub fn fenced_file_block(
+    path: &Path,
+    encoding: Option<&'static Encoding>,
+) -> std::io::Result<String> {
+    let content = read_text(path, encoding)?;
+
+    let longest_backtick_run = content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(3.max(longest_backtick_run + 1));
+
+    let content = content.strip_suffix('\n').unwrap_or(&content);
+    Ok(format!("{}:\n{fence}\n{content}\n{fence}", path.display()))
+}
+
+/// Appends a fenced block for each readable file to `query`,
+/// handing back the paths that had to be skipped along with why.
+pub fn append_file_blocks(
+    query: &mut String,
+    paths: impl IntoIterator<Item = PathBuf>,
+    encoding: Option<&'static Encoding>,
+) -> Vec<(PathBuf, std::io::Error)> {
+    let mut skipped = Vec::new();
+    for path in paths {
+        match fenced_file_block(&path, encoding) {
+            Ok(block) => {
+                query.push_str("\n\n");
+                query.push_str(&block);
+            }
+            Err(e) => skipped.push((path, e)),
+        }
+    }
+    skipped
+}
+
+/// Strips the leading whitespace all non-blank lines have in common, like pasted code
+/// still indented for where it came from. Relative indentation is kept,
+/// whitespace-only lines end up empty and don't count towards the common margin.
+pub fn dedent(text: &str) -> String {
+    let margin = text
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .reduce(|margin, indent| {
+            let common = margin
+                .char_indices()
+                .zip(indent.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(margin.len().min(indent.len()), |((i, _), _)| i);
+            &margin[..common]
+        })
+        .unwrap_or_default();
+
+    text.split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                ""
+            } else {
+                &line[margin.len()..]
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Reads newline-separated paths from a file, or from stdin if `source` is `-`.
+pub fn read_file_list(source: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let list = if source == Path::new("-") {
+        let mut list = String::new();
+        std::io::stdin().read_to_string(&mut list)?;
+        list
+    } else {
+        std::fs::read_to_string(source)?
+    };
+
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_list_skips_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("present.txt");
+        let missing = dir.path().join("missing.txt");
+        std::fs::write(&present, "hello\n").unwrap();
+
+        let list = dir.path().join("list");
+        std::fs::write(&list, format!("{}\n\n{}\n", present.display(), missing.display()))
+            .unwrap();
+
+        let mut query = String::from("summarize these");
+        let skipped = append_file_blocks(&mut query, read_file_list(&list).unwrap(), None);
+
+        assert_eq!(
+            query,
+            format!("summarize these\n\n{}:\n```\nhello\n```", present.display())
+        );
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, missing);
+    }
+
+    #[test]
+    fn common_indentation_is_removed() {
+        let pasted = "    fn main() {\n        println!(\"hi\");\n  \n    }\n";
+        assert_eq!(dedent(pasted), "fn main() {\n    println!(\"hi\");\n\n}\n");
+        // Tabs and spaces only share what's identical
+        assert_eq!(dedent("\t  a\n\t b"), " a\nb");
+        assert_eq!(dedent("a\n    b"), "a\n    b");
+    }
+
+    #[test]
+    fn stdin_fills_the_placeholder_or_follows_the_query() {
+        assert_eq!(
+            insert_stdin("Review this code:\n{stdin}\nFocus on safety", "fn main() {}\n"),
+            "Review this code:\nfn main() {}\nFocus on safety"
+        );
+        assert_eq!(insert_stdin("Review this", "fn main() {}\n"), "Review this\n\nfn main() {}");
+        assert_eq!(insert_stdin("Review this", ""), "Review this");
+    }
+
+    #[test]
+    fn fence_outgrows_backticks_in_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.md");
+        std::fs::write(&path, "```rust\n```").unwrap();
+
+        let block = fenced_file_block(&path, None).unwrap();
+        assert!(block.ends_with("\n````\n```rust\n```\n````"));
+    }
+
+    #[test]
+    fn latin1_file_is_decoded_when_declared() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        // "café" in latin-1
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+
+        let latin1 = Encoding::for_label(b"latin1");
+        let block = fenced_file_block(&path, latin1).unwrap();
+        assert!(block.ends_with("\n```\ncafé\n```"), "{block}");
+
+        let e = fenced_file_block(&path, None).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("--input-encoding"));
+    }
+
+    #[test]
+    fn bytes_invalid_in_the_declared_encoding_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        // A lead byte with nothing to follow it
+        std::fs::write(&path, b"\x82").unwrap();
+
+        let e = read_text(&path, Encoding::for_label(b"shift_jis")).unwrap_err();
+        assert_eq!(e.to_string(), "not valid Shift_JIS");
+    }
+}
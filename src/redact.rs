@@ -0,0 +1,75 @@
+use std::{borrow::Cow, sync::OnceLock};
+
+use regex::Regex;
+
+use crate::ChatHistory;
+
+pub const REPLACEMENT: &str = "[REDACTED]";
+
+/// The redaction `redact_patterns` asks for, set once the config is loaded.
+pub static CONFIGURED: OnceLock<Redactor> = OnceLock::new();
+
+/// Masks whatever its patterns match in a session before it's saved,
+/// leaving the conversation held in memory, and sent, as it is.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+        Ok(Redactor {
+            patterns: patterns.iter().map(|p| Regex::new(p)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Redacts the way the config asks for, or not at all if it hasn't been loaded.
+    pub fn configured() -> &'static Redactor {
+        CONFIGURED.get_or_init(Redactor::default)
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, REPLACEMENT) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text
+    }
+
+    /// The session as it's to be stored.
+    pub fn history<'a>(&self, history: &'a ChatHistory) -> Cow<'a, ChatHistory> {
+        if self.patterns.is_empty() {
+            return Cow::Borrowed(history);
+        }
+
+        let mut redacted = history.clone();
+        for message in &mut redacted.chat.messages {
+            message.content = self.redact(&message.content).into_owned();
+        }
+        redacted.title = redacted.title.map(|title| self.redact(&title).into_owned());
+        Cow::Owned(redacted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_pattern_is_redacted() {
+        let redactor = Redactor::new(&[
+            r"[\w.+-]+@[\w-]+\.[\w.]+".to_owned(),
+            r"sk-[A-Za-z0-9]{8,}".to_owned(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            redactor.redact("mail bob@example.com the key sk-abcdef123456"),
+            "mail [REDACTED] the key [REDACTED]"
+        );
+        assert!(matches!(redactor.redact("nothing here"), Cow::Borrowed(_)));
+        assert!(Redactor::new(&["(".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{run_turn, transcript, ChatHistory, ChatMessage, ChatRole, TurnContext};
+
+/// How a session is titled in listings.
+pub trait TitleStrategy {
+    fn title(&self, history: &ChatHistory) -> Option<String>;
+}
+
+/// The first line of the first user message, shortened to `max_chars`.
+pub struct FirstMessage {
+    pub max_chars: usize,
+}
+
+impl Default for FirstMessage {
+    fn default() -> Self {
+        FirstMessage { max_chars: 50 }
+    }
+}
+
+impl TitleStrategy for FirstMessage {
+    fn title(&self, history: &ChatHistory) -> Option<String> {
+        let first_line = history
+            .chat
+            .messages
+            .iter()
+            .find(|message| matches!(message.role, ChatRole::User))?
+            .content
+            .lines()
+            .find(|line| !line.trim().is_empty())?
+            .trim();
+
+        Some(match first_line.char_indices().nth(self.max_chars) {
+            Some((cut, _)) => format!("{}…", first_line[..cut].trim_end()),
+            None => first_line.to_owned(),
+        })
+    }
+}
+
+/// The title the model came up with, see [`generate`].
+/// Sessions it hasn't titled yet fall back to [`FirstMessage`].
+pub struct ModelSummary;
+
+impl TitleStrategy for ModelSummary {
+    fn title(&self, history: &ChatHistory) -> Option<String> {
+        history
+            .title
+            .clone()
+            .or_else(|| FirstMessage::default().title(history))
+    }
+}
+
+/// Leaves sessions untitled.
+pub struct NoTitle;
+
+impl TitleStrategy for NoTitle {
+    fn title(&self, _history: &ChatHistory) -> Option<String> {
+        None
+    }
+}
+
+/// The built-in strategies, as picked by `title_strategy` in the config.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleStrategyKind {
+    #[default]
+    FirstMessage,
+    ModelSummary,
+    None,
+}
+
+impl TitleStrategyKind {
+    pub fn strategy(self) -> Box<dyn TitleStrategy> {
+        match self {
+            TitleStrategyKind::FirstMessage => Box::new(FirstMessage::default()),
+            TitleStrategyKind::ModelSummary => Box::new(ModelSummary),
+            TitleStrategyKind::None => Box::new(NoTitle),
+        }
+    }
+}
+
+const TITLE_INSTRUCTION: &str =
+    "Give this conversation a title of at most six words. Reply with the title alone.";
+/// The opening exchange says enough about what a conversation is about
+const TITLE_MAX_CHARS: usize = 4_000;
+
+/// Asks the model for a title for `history`, as a separate request that isn't saved.
+pub async fn generate(ctx: &TurnContext<'_>, history: &ChatHistory) -> Option<String> {
+    let opening = &history.chat.messages[..history.chat.messages.len().min(2)];
+    let opening: String = transcript(opening).chars().take(TITLE_MAX_CHARS).collect();
+    let mut request = ChatHistory::new(
+        history.chat.model,
+        vec![ChatMessage {
+            role: ChatRole::User,
+            content: format!("{TITLE_INSTRUCTION}\n\n{opening}"),
+            truncated: false,
+        }],
+    );
+
+    let reply = run_turn(ctx, &mut request, &mut |_: &str| {}).await.ok()?;
+    let title = reply.lines().find(|line| !line.trim().is_empty())?;
+    Some(title.trim().trim_matches(['"', '*']).trim().to_owned())
+}
+
+/// Has the model title `history` under the model_summary strategy, unless it already has a title.
+pub async fn title_if_missing(ctx: &TurnContext<'_>, history: &mut ChatHistory) {
+    if ctx.title_strategy == TitleStrategyKind::ModelSummary && history.title.is_none() {
+        history.title = generate(ctx, history).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn titled(content: &str) -> Option<String> {
+        let history = ChatHistory::new(
+            crate::GPTModelIdent::GPT4oMini,
+            vec![
+                ChatMessage {
+                    role: ChatRole::Assistant,
+                    content: "Not from the user".to_owned(),
+                    truncated: false,
+                },
+                ChatMessage {
+                    role: ChatRole::User,
+                    content: content.to_owned(),
+                    truncated: false,
+                },
+            ],
+        );
+        FirstMessage { max_chars: 10 }.title(&history)
+    }
+
+    #[test]
+    fn first_message_title_is_its_first_line() {
+        assert_eq!(titled("short"), Some("short".to_owned()));
+        assert_eq!(
+            titled("\n  \n  padded  \nsecond line"),
+            Some("padded".to_owned())
+        );
+    }
+
+    #[test]
+    fn long_first_message_is_cut_at_a_char_boundary() {
+        assert_eq!(titled("0123456789"), Some("0123456789".to_owned()));
+        assert_eq!(titled("ünïcödé wörds"), Some("ünïcödé wö…".to_owned()));
+        // No trailing space before the ellipsis
+        assert_eq!(titled("some word  more"), Some("some word…".to_owned()));
+    }
+
+    #[test]
+    fn blank_first_message_has_no_title() {
+        assert_eq!(titled(" \n\t\n"), None);
+    }
+}
@@ -0,0 +1,342 @@
+use std::io::IsTerminal;
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::Stylize,
+    text::{Line, Text},
+    widgets::{Block, Paragraph, Wrap},
+    Frame,
+};
+
+use crate::{
+    confirm, held_back_display, reply_held_back, send_turn, title, ChatHistory, ChatMessage,
+    ChatRole, ModelIdentArg, PastChats, TurnContext,
+};
+
+const PAGE: u16 = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AppAction {
+    None,
+    Send(String),
+    /// A prompt the user already agreed to send
+    SendConfirmed(String),
+    Quit,
+}
+
+/// State of the full-screen interface, kept apart from the terminal so it can be driven by tests.
+pub struct App {
+    transcript: Vec<ChatMessage>,
+    input: String,
+    /// Lines scrolled up from the bottom of the transcript
+    scroll_back: u16,
+    status: String,
+    /// A prompt waiting for the --confirm / size threshold question to be answered
+    awaiting_confirm: Option<String>,
+}
+
+impl App {
+    pub fn new(chat_history: &ChatHistory) -> Self {
+        App {
+            transcript: chat_history.chat.messages.clone(),
+            input: String::new(),
+            scroll_back: 0,
+            status: "Enter sends, PgUp/PgDn scroll, Ctrl-C quits".to_owned(),
+            awaiting_confirm: None,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &Event) -> AppAction {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event
+        else {
+            return AppAction::None;
+        };
+
+        if modifiers.contains(KeyModifiers::CONTROL) {
+            return match code {
+                KeyCode::Char('c') => AppAction::Quit,
+                KeyCode::Char('d') if self.input.is_empty() => AppAction::Quit,
+                _ => AppAction::None,
+            };
+        }
+
+        if let Some(query) = self.awaiting_confirm.take() {
+            if matches!(code, KeyCode::Char('y' | 'Y')) {
+                return AppAction::SendConfirmed(query);
+            }
+            self.status = "Aborted.".to_owned();
+            self.input = query;
+            return AppAction::None;
+        }
+
+        match code {
+            KeyCode::Char(c) => self.input.push(*c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter if !self.input.trim().is_empty() => {
+                return AppAction::Send(std::mem::take(&mut self.input));
+            }
+            KeyCode::Up => self.scroll_back = self.scroll_back.saturating_add(1),
+            KeyCode::Down => self.scroll_back = self.scroll_back.saturating_sub(1),
+            KeyCode::PageUp => self.scroll_back = self.scroll_back.saturating_add(PAGE),
+            KeyCode::PageDown => self.scroll_back = self.scroll_back.saturating_sub(PAGE),
+            _ => {}
+        }
+        AppAction::None
+    }
+
+    /// Holds `query` back until the user answers `question` with y.
+    fn ask_confirm(&mut self, query: String, question: String) {
+        self.status = format!("{question} [y/N]");
+        self.awaiting_confirm = Some(query);
+    }
+
+    fn begin_turn(&mut self, query: &str) {
+        self.transcript.push(ChatMessage {
+            role: ChatRole::User,
+            content: query.to_owned(),
+            truncated: false,
+        });
+        self.transcript.push(ChatMessage {
+            role: ChatRole::Assistant,
+            content: String::new(),
+            truncated: false,
+        });
+        // Follow the reply as it streams in
+        self.scroll_back = 0;
+        self.status = "Waiting for the reply ...".to_owned();
+    }
+
+    fn push_fragment(&mut self, fragment: &str) {
+        if let Some(reply) = self.transcript.last_mut() {
+            reply.content.push_str(fragment);
+        }
+    }
+
+    fn replace_reply(&mut self, reply: String) {
+        if let Some(last) = self.transcript.last_mut() {
+            last.content = reply;
+        }
+    }
+
+    /// Takes a failed turn back out of the transcript, handing its prompt back for editing.
+    fn abandon_turn(&mut self, status: String) {
+        self.transcript.truncate(self.transcript.len().saturating_sub(2));
+        self.status = status;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [transcript_area, input_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+
+        let mut lines = Vec::new();
+        for message in &self.transcript {
+            lines.push(match message.role {
+                ChatRole::User => Line::from("you").bold().cyan(),
+                ChatRole::Assistant => Line::from("assistant").bold().green(),
+            });
+            lines.extend(message.content.lines().map(Line::from));
+            lines.push(Line::default());
+        }
+        let transcript = Paragraph::new(Text::from(lines)).wrap(Wrap { trim: false });
+
+        let visible = transcript_area.height.saturating_sub(2);
+        let total = transcript.line_count(transcript_area.width.saturating_sub(2));
+        let max_scroll = u16::try_from(total)
+            .unwrap_or(u16::MAX)
+            .saturating_sub(visible);
+        self.scroll_back = self.scroll_back.min(max_scroll);
+
+        frame.render_widget(
+            transcript
+                .scroll((max_scroll - self.scroll_back, 0))
+                .block(Block::bordered().title("ddgpt")),
+            transcript_area,
+        );
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .block(Block::bordered().title(self.status.as_str())),
+            input_area,
+        );
+    }
+}
+
+/// Runs the full-screen interface until Ctrl-C, saving the session on the way out.
+pub async fn run(
+    ctx: &TurnContext<'_>,
+    mut chat_history: ChatHistory,
+    mut session_name: Option<String>,
+    first_query: Option<String>,
+) -> std::io::Result<()> {
+    let mut app = App::new(&chat_history);
+    let mut terminal = ratatui::init();
+    let mut pending_query = first_query;
+
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| app.draw(frame)) {
+            break Err(e);
+        }
+
+        let (query, confirmed) = match pending_query.take() {
+            Some(query) => (query, false),
+            None => match event::read().map(|event| app.handle_event(&event)) {
+                Ok(AppAction::None) => continue,
+                Ok(AppAction::Quit) => break Ok(()),
+                Ok(AppAction::Send(query)) => (query, false),
+                Ok(AppAction::SendConfirmed(query)) => (query, true),
+                Err(e) => break Err(e),
+            },
+        };
+
+        let over_threshold = ctx
+            .confirm_above_bytes
+            .is_some_and(|limit| query.len() > limit);
+        let decision = confirm::confirm_decision(
+            over_threshold,
+            ctx.args.confirm,
+            ctx.args.assume_yes,
+            std::io::stdin().is_terminal(),
+        );
+        if !confirmed && decision == confirm::ConfirmDecision::Abort {
+            app.status = "Error: --confirm needs a terminal to ask on, pass --yes to send anyway"
+                .to_owned();
+            app.input = query;
+            continue;
+        }
+        if !confirmed && decision == confirm::ConfirmDecision::Ask {
+            let question = format!(
+                "Send {} bytes to model {}?",
+                query.len(),
+                ModelIdentArg::from(chat_history.chat.model).as_str()
+            );
+            app.ask_confirm(query, question);
+            continue;
+        }
+
+        app.begin_turn(&query);
+        let turn_result = send_turn(ctx, &mut chat_history, query.clone(), &mut |fragment: &str| {
+            if !reply_held_back(ctx.args) {
+                app.push_fragment(fragment);
+                let _ = terminal.draw(|frame| app.draw(frame));
+            }
+        })
+        .await;
+        // Status output on stderr lands on top of the interface, repaint it all
+        let _ = terminal.clear();
+
+        match turn_result {
+            Ok(reply) if !reply.is_empty() => {
+                if reply_held_back(ctx.args) {
+                    app.replace_reply(held_back_display(ctx.args, &reply));
+                }
+                app.status = "Enter sends, PgUp/PgDn scroll, Ctrl-C quits".to_owned();
+            }
+            Err(e) if e.partial_reply().is_some() => {
+                if reply_held_back(ctx.args) {
+                    let partial_reply = e.partial_reply().unwrap_or_default();
+                    app.replace_reply(held_back_display(ctx.args, partial_reply));
+                }
+                app.status = format!("Error: {e}, kept what arrived as truncated");
+            }
+            result => {
+                let status = match result {
+                    Err(e) => format!("Error: {e}"),
+                    Ok(_) => "The chatbot sent an empty reply".to_owned(),
+                };
+                app.abandon_turn(status);
+                app.input = query;
+            }
+        }
+    };
+
+    ratatui::restore();
+
+    if !chat_history.chat.messages.is_empty() {
+        title::title_if_missing(ctx, &mut chat_history).await;
+        if let Err(e) =
+            PastChats::save_to(&mut session_name, &mut chat_history, ctx.max_session_bytes)
+        {
+            eprintln!("Error: failed to save the chat: {e}");
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use super::*;
+    use crate::GPTModelIdent;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Event {
+        Event::Key(KeyEvent::new(code, modifiers))
+    }
+
+    #[test]
+    fn app_processes_scripted_input() {
+        let chat_history = ChatHistory::new(
+            GPTModelIdent::GPT4oMini,
+            vec![ChatMessage {
+                role: ChatRole::User,
+                content: "earlier question".to_owned(),
+                truncated: false,
+            }],
+        );
+        let mut app = App::new(&chat_history);
+
+        for c in "hi".chars() {
+            assert_eq!(
+                app.handle_event(&key(KeyCode::Char(c), KeyModifiers::NONE)),
+                AppAction::None
+            );
+        }
+
+        let mut terminal = Terminal::new(TestBackend::new(40, 12)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("earlier question"));
+        assert!(screen.contains("hi"));
+
+        assert_eq!(
+            app.handle_event(&key(KeyCode::Enter, KeyModifiers::NONE)),
+            AppAction::Send("hi".to_owned())
+        );
+        assert!(app.input.is_empty());
+        assert_eq!(
+            app.handle_event(&key(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            AppAction::Quit
+        );
+    }
+
+    #[test]
+    fn declined_confirmation_keeps_the_prompt() {
+        let mut app = App::new(&ChatHistory::new(GPTModelIdent::GPT4oMini, vec![]));
+
+        app.ask_confirm("big prompt".to_owned(), "Send it?".to_owned());
+        assert_eq!(
+            app.handle_event(&key(KeyCode::Char('n'), KeyModifiers::NONE)),
+            AppAction::None
+        );
+        assert_eq!(app.input, "big prompt");
+
+        app.ask_confirm("big prompt".to_owned(), "Send it?".to_owned());
+        assert_eq!(
+            app.handle_event(&key(KeyCode::Char('y'), KeyModifiers::NONE)),
+            AppAction::SendConfirmed("big prompt".to_owned())
+        );
+    }
+}